@@ -0,0 +1,73 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Request, Response, Server, Uri};
+use tokio::runtime::Runtime;
+
+use crate::upstream_client::{UpstreamClientSettings, UpstreamClients};
+
+// hapi is a binary crate, so the upstream client module is compiled into the benchmark directly.
+// Its lints are already reported when checking the binary
+#[allow(dead_code, clippy::all)]
+#[path = "../src/infrastructure/upstream_client.rs"]
+mod upstream_client;
+
+const REQUESTS_PER_ITERATION: u64 = 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5); // default `connect_ms` of the timeouts
+
+/// Starts a local upstream answering every request with an empty response
+fn start_upstream(runtime: &Runtime) -> SocketAddr {
+    runtime.block_on(async {
+        let make_service = make_service_fn(|_conn| async {
+            Ok::<_, Infallible>(service_fn(|_request: Request<Body>| async {
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            }))
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    })
+}
+
+fn bench_upstream_client(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let upstream = start_upstream(&runtime);
+    let uri: Uri = format!("http://{}/users", upstream).parse().unwrap();
+
+    let mut group = c.benchmark_group("upstream_client");
+    group.throughput(Throughput::Elements(REQUESTS_PER_ITERATION));
+
+    // what the proxy used to do: a new client, hence a new connection, for every request
+    group.bench_function("client_per_request", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for _ in 0..REQUESTS_PER_ITERATION {
+                    let client = Client::new();
+                    client.get(uri.clone()).await.unwrap();
+                }
+            })
+        })
+    });
+
+    // the clients the proxy shares across requests, with the default settings
+    let client = UpstreamClients::build(&UpstreamClientSettings::default())
+        .with_connect_timeout(CONNECT_TIMEOUT);
+    group.bench_function("shared_client", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                for _ in 0..REQUESTS_PER_ITERATION {
+                    client.get(uri.clone()).await.unwrap();
+                }
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_upstream_client);
+criterion_main!(benches);
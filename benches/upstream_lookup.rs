@@ -0,0 +1,67 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use crate::modules::core::context::{Context, LookupRequest};
+use crate::modules::core::route::Route;
+use crate::modules::core::upstream::{Upstream, UpstreamStrategy};
+
+// hapi is a binary crate, so the core module is compiled into the benchmark directly. Its lints
+// are already reported when checking the binary
+#[allow(dead_code, unused_imports, clippy::all)]
+#[path = "../src/modules"]
+mod modules {
+    pub(crate) mod core;
+}
+
+const ROUTE_COUNT: usize = 1000;
+
+fn context_with_routes() -> Context {
+    let mut context = Context::build_empty();
+
+    for i in 0..ROUTE_COUNT {
+        let path = match i % 3 {
+            0 => format!("/exact/{}", i),
+            1 => format!("/template/{}/{{id}}", i),
+            _ => format!("^/regexp/{}/.*$", i),
+        };
+        let upstreams = vec![
+            Upstream::build_from_fqdn(format!("upstream{}:8080", i).as_str()),
+            Upstream::build_from_fqdn(format!("upstream{}:8081", i).as_str()),
+        ];
+        let route = Route::build(
+            format!("id{}", i),
+            format!("route{}", i),
+            vec![String::from("GET"), String::from("POST")],
+            vec![path],
+            UpstreamStrategy::RoundRobin {
+                upstreams,
+                next_index: 0,
+            },
+        );
+        context.add_route(route).unwrap();
+    }
+
+    context
+}
+
+fn upstream_lookup(c: &mut Criterion) {
+    let mut context = context_with_routes();
+    let mut group = c.benchmark_group("upstream_lookup_1k_routes");
+
+    let cases = [
+        ("exact", "/exact/999"),
+        ("template", "/template/997/42"),
+        ("regexp", "/regexp/998/a/b/c"),
+        ("miss", "/not/routed"),
+    ];
+    for (name, path) in cases.iter() {
+        let request = LookupRequest::build(path, "GET");
+        group.bench_function(*name, |b| {
+            b.iter(|| context.upstream_lookup(black_box(&request)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, upstream_lookup);
+criterion_main!(benches);
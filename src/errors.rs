@@ -19,7 +19,7 @@ pub(crate) enum HapiError {
     CommandSendError(SendError<Command>),
     CoreError(CoreError),
     MessageReceiveError(RecvError),
-    EventSendError(SendError<Event>),
+    EventSendError(Box<SendError<Event>>),
 }
 
 impl Display for HapiError {
@@ -36,7 +36,7 @@ impl Display for HapiError {
             HapiError::CommandSendError(tokio_send_msg_error) => {
                 write!(f, "{:?}", tokio_send_msg_error)
             }
-            HapiError::CoreError(core_error) => write!(f, "{:?}", core_error),
+            HapiError::CoreError(core_error) => write!(f, "{}", core_error),
             HapiError::MessageReceiveError(recv_error) => write!(f, "{:?}", recv_error),
             HapiError::EventSendError(tokio_send_msg_error) => {
                 write!(f, "{:?}", tokio_send_msg_error)
@@ -103,6 +103,6 @@ impl From<RecvError> for HapiError {
 
 impl From<SendError<Event>> for HapiError {
     fn from(tokio_send_msg_error: SendError<Event>) -> Self {
-        HapiError::EventSendError(tokio_send_msg_error)
+        HapiError::EventSendError(Box::new(tokio_send_msg_error))
     }
 }
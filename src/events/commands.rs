@@ -1,5 +1,7 @@
 use crate::modules::core::route::Route;
 use crate::modules::core::upstream::UpstreamAddress;
+use crate::modules::stats::Outcome;
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub(crate) enum Command {
@@ -7,8 +9,12 @@ pub(crate) enum Command {
     LookupUpstream {
         id: String,
         client: String,
+        host: Option<String>,
         path: String,
         method: String,
+        headers: Vec<(String, String)>,
+        query: Vec<(String, String)>,
+        excluded_upstreams: Vec<UpstreamAddress>,
     },
     EnableUpstream {
         id: String,
@@ -20,7 +26,7 @@ pub(crate) enum Command {
     },
     AddRoute {
         id: String,
-        route: Route,
+        route: Box<Route>,
     },
     RemoveRoute {
         id: String,
@@ -36,9 +42,65 @@ pub(crate) enum Command {
     LookupAllUpstreams {
         id: String,
     },
+    SetTrafficSplit {
+        id: String,
+        route_id: String,
+        percentage: u8,
+    },
+    CompleteRequest {
+        id: String,
+        route_id: String,
+        upstream_address: UpstreamAddress,
+        latency: Option<Duration>, // time to the upstream response headers, if any
+        failed: bool,              // connection error, timeout or 5xx response
+    },
+    OpenCircuit {
+        id: String,
+        upstream_address: UpstreamAddress,
+    },
+    HalfOpenCircuit {
+        id: String,
+        upstream_address: UpstreamAddress,
+        trial_requests: usize,
+    },
+    CloseCircuit {
+        id: String,
+        upstream_address: UpstreamAddress,
+    },
+    EjectUpstream {
+        id: String,
+        route_id: String,
+        upstream_address: UpstreamAddress,
+        duration: Duration, // until the upstream gets restored
+    },
+    RestoreUpstream {
+        id: String,
+        route_id: String,
+        upstream_address: UpstreamAddress,
+    },
 
     // Stats commands
     LookupStats {
         id: String,
     },
+    LookupOutcomes {
+        id: String,
+    },
+    LookupEjections {
+        id: String,
+    },
+    LookupFailovers {
+        id: String,
+    },
+    CountOutcome {
+        id: String,
+        route_id: String,
+        upstream_address: UpstreamAddress,
+        outcome: Outcome,
+    },
+
+    // Circuit breaker commands
+    LookupCircuits {
+        id: String,
+    },
 }
@@ -1,5 +1,5 @@
 use crate::modules::core::context::CoreError;
-use crate::modules::core::route::Route;
+use crate::modules::core::route::{PathParams, RetryPolicy, Route, UpstreamTimeouts};
 use crate::modules::core::upstream::UpstreamAddress;
 
 #[derive(Clone, Debug)]
@@ -8,12 +8,24 @@ pub(crate) enum Event {
     UpstreamWasFound {
         cmd_id: String,
         upstream_address: UpstreamAddress,
+        upstream_path: String, // path to forward to the upstream, after rewrites
         client: String,
         path: String,
         method: String,
+        route_id: String,
+        params: PathParams,
+        affinity_cookie: Option<(String, String)>,
+        sticky_cookie: Option<String>,
+        mirror: Option<UpstreamAddress>,
+        timeouts: UpstreamTimeouts,
+        retry: Option<RetryPolicy>,
+    },
+    RouteWasNotMatched {
+        cmd_id: String,
     },
-    UpstreamWasNotFound {
+    NoHealthyUpstream {
         cmd_id: String,
+        route_id: String, // the matched route, none of whose upstreams is available
     },
     UpstreamWasEnabled {
         cmd_id: String,
@@ -26,6 +38,7 @@ pub(crate) enum Event {
     RouteWasAdded {
         cmd_id: String,
         route: Route,
+        shadowed_by: Vec<String>, // ids of the routes that completely shadow the added one
     },
     RouteWasNotAdded {
         cmd_id: String,
@@ -57,10 +70,53 @@ pub(crate) enum Event {
         cmd_id: String,
         upstreams: Vec<UpstreamAddress>,
     },
+    TrafficSplitWasSet {
+        cmd_id: String,
+        route: Route,
+    },
+    TrafficSplitWasNotSet {
+        cmd_id: String,
+        error: CoreError,
+    },
+    RouteWasFailedOver {
+        route_id: String,
+        to_tier: Option<usize>, // None when no tier has enabled upstreams
+    },
+    RouteWasFailedBack {
+        route_id: String,
+        to_tier: usize,
+    },
+    UpstreamWasEjected {
+        route_id: String,
+        upstream_address: UpstreamAddress,
+    },
+    UpstreamWasRestored {
+        route_id: String,
+        upstream_address: UpstreamAddress,
+    },
 
     // Stats events
     StatsWereFound {
         cmd_id: String,
         stats: Vec<(String, String, String, String, u64)>,
     },
+    OutcomesWereFound {
+        cmd_id: String,
+        outcomes: Vec<(String, String, String, u64)>, // (route id, upstream, outcome, count)
+    },
+    EjectionsWereFound {
+        cmd_id: String,
+        ejections: Vec<(String, String, u64, bool)>, // (route id, upstream, count, ejected now)
+    },
+    FailoversWereFound {
+        cmd_id: String,
+        // (route id, times failed over, times failed back, active tier)
+        failovers: Vec<(String, u64, u64, Option<usize>)>,
+    },
+
+    // Circuit breaker events
+    CircuitsWereFound {
+        cmd_id: String,
+        circuits: Vec<(String, String)>, // (upstream, state)
+    },
 }
@@ -0,0 +1,216 @@
+use crate::errors::HapiError;
+use crate::events::commands::Command;
+use crate::events::commands::Command::{
+    CloseCircuit, CompleteRequest, HalfOpenCircuit, LookupCircuits, OpenCircuit,
+};
+use crate::events::events::Event;
+use crate::events::events::Event::CircuitsWereFound;
+use crate::infrastructure::settings::CircuitBreakerSettings;
+use crate::modules::breaker::{CircuitBreaker, CircuitState};
+use crate::modules::core::upstream::UpstreamAddress;
+use std::collections::HashMap;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::sleep;
+use uuid::Uuid;
+
+/// Feeds the circuit breaker of every upstream with the outcome of the requests forwarded to it,
+/// and tells the core to open, half-open or close circuits as they change state
+pub(crate) async fn handle_breakers(
+    mut recv_cmd: Receiver<Command>,
+    send_cmd: Sender<Command>,
+    send_evt: Sender<Event>,
+    settings: CircuitBreakerSettings,
+) {
+    let mut breaker_controller = BreakerController::build(send_cmd, settings);
+
+    loop {
+        // a lagging receiver only missed some outcomes, so breakers keep going until the bus closes
+        let command = match recv_cmd.recv().await {
+            Ok(command) => command,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Circuit breakers skipped {} commands", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let maybe_event = match command {
+            CompleteRequest {
+                upstream_address,
+                latency,
+                failed,
+                ..
+            } => {
+                if failed || latency.is_some() {
+                    breaker_controller.record(&upstream_address, failed);
+                } else {
+                    breaker_controller.record_abandoned(&upstream_address);
+                }
+                None
+            }
+            HalfOpenCircuit {
+                upstream_address, ..
+            } => {
+                breaker_controller.half_open(&upstream_address);
+                None
+            }
+            LookupCircuits { id } => Some(CircuitsWereFound {
+                cmd_id: id,
+                circuits: breaker_controller.get_circuits(),
+            }),
+            _ => None,
+        };
+
+        if let Some(event) = maybe_event {
+            match send_evt.send(event) {
+                Ok(_) => log::debug!("Event sent"),
+                Err(e) => log::error!("Error sending event {}", e),
+            }
+        }
+    }
+}
+
+struct BreakerController {
+    breakers: HashMap<UpstreamAddress, CircuitBreaker>,
+    send_cmd: Sender<Command>,
+    settings: CircuitBreakerSettings,
+}
+
+impl BreakerController {
+    fn build(send_cmd: Sender<Command>, settings: CircuitBreakerSettings) -> Self {
+        BreakerController {
+            breakers: HashMap::new(),
+            send_cmd,
+            settings,
+        }
+    }
+
+    fn record(&mut self, upstream_address: &UpstreamAddress, failed: bool) {
+        if !self.settings.enabled {
+            return;
+        }
+        let settings = &self.settings;
+        let breaker = self
+            .breakers
+            .entry(upstream_address.clone())
+            .or_insert_with(|| {
+                CircuitBreaker::build(
+                    settings.consecutive_failures,
+                    settings.error_rate,
+                    settings.min_requests,
+                    settings.window,
+                    settings.trial_requests,
+                )
+            });
+        let change = breaker.record(failed);
+        self.apply(upstream_address, change);
+    }
+
+    fn record_abandoned(&mut self, upstream_address: &UpstreamAddress) {
+        let change = self
+            .breakers
+            .get_mut(upstream_address)
+            .and_then(|breaker| breaker.record_abandoned());
+        self.apply(upstream_address, change);
+    }
+
+    fn half_open(&mut self, upstream_address: &UpstreamAddress) {
+        if let Some(breaker) = self.breakers.get_mut(upstream_address) {
+            if breaker.half_open() {
+                log::info!("Circuit of upstream {} is half-open", upstream_address);
+            }
+        }
+    }
+
+    /// Returns the (upstream, state) of every circuit, sorted by upstream
+    fn get_circuits(&self) -> Vec<(String, String)> {
+        let mut result: Vec<(String, String)> = self
+            .breakers
+            .iter()
+            .map(|(upstream, breaker)| (upstream.to_string(), breaker.state().to_string()))
+            .collect();
+        result.sort();
+        result
+    }
+
+    /// Tells the core about the new state of the circuit of the given upstream, if it changed.
+    /// Open circuits get half-opened once the cool-down is over
+    fn apply(&self, upstream_address: &UpstreamAddress, change: Option<CircuitState>) {
+        let command = match change {
+            Some(CircuitState::Open) => {
+                log::warn!("Circuit of upstream {} is open", upstream_address);
+                self.schedule_half_open(upstream_address);
+                OpenCircuit {
+                    id: Uuid::new_v4().to_string(),
+                    upstream_address: upstream_address.clone(),
+                }
+            }
+            Some(CircuitState::Closed) => {
+                log::info!("Circuit of upstream {} is closed", upstream_address);
+                CloseCircuit {
+                    id: Uuid::new_v4().to_string(),
+                    upstream_address: upstream_address.clone(),
+                }
+            }
+            _ => return,
+        };
+        match self.send_cmd.send(command) {
+            Ok(_) => log::debug!("Command sent"),
+            Err(e) => log::error!("Error sending command {}", e),
+        }
+    }
+
+    fn schedule_half_open(&self, upstream_address: &UpstreamAddress) {
+        let command = HalfOpenCircuit {
+            id: Uuid::new_v4().to_string(),
+            upstream_address: upstream_address.clone(),
+            trial_requests: self.settings.trial_requests,
+        };
+        let send_cmd = self.send_cmd.clone();
+        let cool_down = self.settings.cool_down();
+        tokio::spawn(async move {
+            sleep(cool_down).await;
+            match send_cmd.send(command) {
+                Ok(_) => log::debug!("Command sent"),
+                Err(e) => log::error!("Error sending command {}", e),
+            }
+        });
+    }
+}
+
+pub(crate) struct BreakerClient {
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+}
+
+impl BreakerClient {
+    pub fn build(send_cmd: Sender<Command>, recv_evt: Receiver<Event>) -> Self {
+        Self { send_cmd, recv_evt }
+    }
+
+    /// Returns the (upstream, state) of the circuit of every upstream that got requests
+    pub async fn get_circuits(&mut self) -> Result<Vec<(String, String)>, HapiError> {
+        let cmd_uuid = Uuid::new_v4();
+        let command = LookupCircuits {
+            id: cmd_uuid.to_string(),
+        };
+        self.send_cmd.send(command)?;
+
+        loop {
+            match self.recv_evt.recv().await {
+                Ok(event) => {
+                    log::debug!("Received event {:?}", event);
+                    if let CircuitsWereFound { cmd_id, circuits } = event {
+                        if cmd_id == cmd_uuid.to_string() {
+                            break Ok(circuits);
+                        }
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Error receiving message {:?}", error);
+                    break Err(HapiError::MessageReceiveError(error));
+                }
+            }
+        }
+    }
+}
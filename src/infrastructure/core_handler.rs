@@ -4,16 +4,19 @@ use uuid::Uuid;
 use crate::errors::HapiError;
 use crate::events::commands::Command;
 use crate::events::commands::Command::{
-    AddRoute, DisableUpstream, EnableUpstream, LookupAllRoutes, LookupAllUpstreams, LookupRoute,
-    LookupUpstream, RemoveRoute,
+    AddRoute, CloseCircuit, CompleteRequest, DisableUpstream, EjectUpstream, EnableUpstream,
+    HalfOpenCircuit, LookupAllRoutes, LookupAllUpstreams, LookupRoute, LookupUpstream, OpenCircuit,
+    RemoveRoute, RestoreUpstream, SetTrafficSplit,
 };
 use crate::events::events::Event;
 use crate::events::events::Event::{
-    RouteWasAdded, RouteWasFound, RouteWasNotAdded, RouteWasNotFound, RouteWasNotRemoved,
-    RouteWasRemoved, RoutesWereFound, UpstreamWasDisabled, UpstreamWasEnabled, UpstreamWasFound,
-    UpstreamWasNotFound, UpstreamsWereFound,
+    NoHealthyUpstream, RouteWasAdded, RouteWasFailedBack, RouteWasFailedOver, RouteWasFound,
+    RouteWasNotAdded, RouteWasNotFound, RouteWasNotMatched, RouteWasNotRemoved, RouteWasRemoved,
+    RoutesWereFound, TrafficSplitWasNotSet, TrafficSplitWasSet, UpstreamWasDisabled,
+    UpstreamWasEjected, UpstreamWasEnabled, UpstreamWasFound, UpstreamWasRestored,
+    UpstreamsWereFound,
 };
-use crate::modules::core::context::Context;
+use crate::modules::core::context::{Context, CoreError, LookupRequest, TierChange, UpstreamMatch};
 use crate::modules::core::route::Route;
 use crate::modules::core::upstream::UpstreamAddress;
 use crate::repositories::jsonfile::JsonFile;
@@ -30,28 +33,60 @@ pub(crate) async fn handle_core(mut recv_cmd: Receiver<Command>, send_evt: Sende
             LookupUpstream {
                 id,
                 client,
+                host,
                 path,
                 method,
+                headers,
+                query,
+                excluded_upstreams,
             } => {
-                match context.upstream_lookup(path.as_str(), method.as_str()) {
+                let request = LookupRequest {
+                    client: client.clone(),
+                    host,
+                    path,
+                    method,
+                    headers,
+                    query,
+                    excluded_upstreams,
+                };
+                match context.upstream_lookup(&request) {
                     Ok(maybe_upstream) => match maybe_upstream {
                         Some(upstream) => Some(UpstreamWasFound {
                             cmd_id: id.clone(),
-                            upstream_address: upstream.address.clone(),
+                            upstream_address: upstream.upstream_address,
+                            upstream_path: upstream.upstream_path,
                             client,
-                            path,
-                            method,
+                            path: request.path,
+                            method: request.method,
+                            route_id: upstream.route_id,
+                            params: upstream.params,
+                            affinity_cookie: upstream.affinity_cookie,
+                            sticky_cookie: upstream.sticky_cookie,
+                            mirror: upstream.mirror,
+                            timeouts: upstream.timeouts,
+                            retry: upstream.retry,
                         }),
-                        None => Some(UpstreamWasNotFound { cmd_id: id.clone() }),
+                        None => Some(RouteWasNotMatched { cmd_id: id.clone() }),
                     },
+                    Err(CoreError::NoHealthyUpstream(route_id)) => Some(NoHealthyUpstream {
+                        cmd_id: id.clone(),
+                        route_id,
+                    }),
                     Err(_error) => None, // TODO: map error to proper event
                 }
             }
-            AddRoute { id, route } => match context.add_route(route.clone()) {
-                Ok(_) => Some(RouteWasAdded { cmd_id: id, route }),
+            AddRoute { id, route } => match context.add_route((*route).clone()) {
+                Ok(_) => {
+                    let shadowed_by = shadowing_routes_for(&context, &route);
+                    Some(RouteWasAdded {
+                        cmd_id: id,
+                        route: *route,
+                        shadowed_by,
+                    })
+                }
                 Err(error) => Some(RouteWasNotAdded {
                     cmd_id: id,
-                    route,
+                    route: *route,
                     error,
                 }),
             },
@@ -71,10 +106,13 @@ pub(crate) async fn handle_core(mut recv_cmd: Receiver<Command>, send_evt: Sende
                 upstream_address,
             } => {
                 match context.enable_upstream_for_all_routes(&upstream_address) {
-                    Ok(_) => Some(UpstreamWasEnabled {
-                        cmd_id: id,
-                        upstream_address,
-                    }),
+                    Ok(tier_changes) => {
+                        send_tier_changes(&send_evt, tier_changes);
+                        Some(UpstreamWasEnabled {
+                            cmd_id: id,
+                            upstream_address,
+                        })
+                    }
                     Err(_error) => None, // TODO: map error to proper event
                 }
             }
@@ -83,10 +121,13 @@ pub(crate) async fn handle_core(mut recv_cmd: Receiver<Command>, send_evt: Sende
                 upstream_address,
             } => {
                 match context.disable_upstream_for_all_routes(&upstream_address) {
-                    Ok(_) => Some(UpstreamWasDisabled {
-                        cmd_id: id,
-                        upstream_address,
-                    }),
+                    Ok(tier_changes) => {
+                        send_tier_changes(&send_evt, tier_changes);
+                        Some(UpstreamWasDisabled {
+                            cmd_id: id,
+                            upstream_address,
+                        })
+                    }
                     Err(_error) => None, // TODO: map error to proper event
                 }
             }
@@ -132,6 +173,114 @@ pub(crate) async fn handle_core(mut recv_cmd: Receiver<Command>, send_evt: Sende
                     Err(_error) => None, // TODO: map error to proper event
                 }
             }
+            SetTrafficSplit {
+                id,
+                route_id,
+                percentage,
+            } => match context.set_traffic_split(route_id.as_str(), percentage) {
+                Ok(route) => Some(TrafficSplitWasSet {
+                    cmd_id: id,
+                    route: route.clone(),
+                }),
+                Err(error) => Some(TrafficSplitWasNotSet { cmd_id: id, error }),
+            },
+            CompleteRequest {
+                id,
+                route_id,
+                upstream_address,
+                latency,
+                ..
+            } => {
+                let result = context.complete_request(&route_id, &upstream_address, latency);
+                if let Err(error) = result {
+                    log::error!("Could not complete request {}: {}", id, error);
+                }
+                None // nobody waits for completions
+            }
+            OpenCircuit {
+                id,
+                upstream_address,
+            } => {
+                match context.open_circuit(&upstream_address) {
+                    Ok(tier_changes) => send_tier_changes(&send_evt, tier_changes),
+                    Err(error) => log::error!("Could not open circuit {}: {}", id, error),
+                }
+                None // the circuit breaker doesn't wait for the core
+            }
+            HalfOpenCircuit {
+                id,
+                upstream_address,
+                trial_requests,
+            } => {
+                let result = context.half_open_circuit(&upstream_address, trial_requests);
+                if let Err(error) = result {
+                    log::error!("Could not half-open circuit {}: {}", id, error);
+                }
+                None // the circuit breaker doesn't wait for the core
+            }
+            CloseCircuit {
+                id,
+                upstream_address,
+            } => {
+                match context.close_circuit(&upstream_address) {
+                    Ok(tier_changes) => send_tier_changes(&send_evt, tier_changes),
+                    Err(error) => log::error!("Could not close circuit {}: {}", id, error),
+                }
+                None // the circuit breaker doesn't wait for the core
+            }
+            EjectUpstream {
+                id,
+                route_id,
+                upstream_address,
+                duration,
+            } => match context.eject_upstream(&route_id, &upstream_address) {
+                Ok(tier_changes) => {
+                    log::info!(
+                        "Ejected {} from {} for {:?}",
+                        upstream_address,
+                        route_id,
+                        duration
+                    );
+                    send_tier_changes(&send_evt, tier_changes);
+                    Some(UpstreamWasEjected {
+                        route_id,
+                        upstream_address,
+                    })
+                }
+                Err(error) => {
+                    log::warn!(
+                        "Could not eject {} from {} ({}): {}",
+                        upstream_address,
+                        route_id,
+                        id,
+                        error
+                    );
+                    None
+                }
+            },
+            RestoreUpstream {
+                id,
+                route_id,
+                upstream_address,
+            } => match context.restore_upstream(&route_id, &upstream_address) {
+                Ok(tier_changes) => {
+                    send_tier_changes(&send_evt, tier_changes);
+                    Some(UpstreamWasRestored {
+                        route_id,
+                        upstream_address,
+                    })
+                }
+                Err(error) => {
+                    log::warn!(
+                        "Could not restore {} to {} ({}): {}",
+                        upstream_address,
+                        route_id,
+                        id,
+                        error
+                    );
+                    None
+                }
+            },
             _ => None,
         };
 
@@ -216,18 +365,23 @@ impl CoreClient {
         }
     }
 
+    /// Returns the upstream to forward the given request to, or `None` if no route matches it.
+    /// Fails with a `NoHealthyUpstream` core error if the matched route has no upstream left
     pub async fn search_upstream(
         &mut self,
         client: &str,
-        path: &str,
-        method: &str,
-    ) -> Result<Option<UpstreamAddress>, HapiError> {
+        request: LookupRequest,
+    ) -> Result<Option<UpstreamMatch>, HapiError> {
         let cmd_uuid = Uuid::new_v4();
         let command = LookupUpstream {
             id: cmd_uuid.to_string(),
             client: client.to_string(),
-            path: path.to_string(),
-            method: method.to_string(),
+            host: request.host,
+            path: request.path,
+            method: request.method,
+            headers: request.headers,
+            query: request.query,
+            excluded_upstreams: request.excluded_upstreams,
         };
         self.send_cmd.send(command)?;
 
@@ -239,16 +393,38 @@ impl CoreClient {
                         UpstreamWasFound {
                             cmd_id,
                             upstream_address,
+                            upstream_path,
+                            route_id,
+                            params,
+                            affinity_cookie,
+                            sticky_cookie,
+                            mirror,
+                            timeouts,
+                            retry,
                             ..
                         } => {
                             if cmd_id == cmd_uuid.to_string() {
-                                break Ok(Some(upstream_address.clone()));
+                                break Ok(Some(UpstreamMatch {
+                                    route_id,
+                                    upstream_address,
+                                    upstream_path,
+                                    params,
+                                    affinity_cookie,
+                                    sticky_cookie,
+                                    mirror,
+                                    timeouts,
+                                    retry,
+                                }));
                             }
                         }
-                        UpstreamWasNotFound { cmd_id } => {
-                            if cmd_id == cmd_uuid.to_string() {
-                                break Ok(None);
-                            }
+                        RouteWasNotMatched { cmd_id } if cmd_id == cmd_uuid.to_string() => {
+                            break Ok(None);
+                        }
+                        NoHealthyUpstream { cmd_id, route_id }
+                            if cmd_id == cmd_uuid.to_string() =>
+                        {
+                            let error = CoreError::NoHealthyUpstream(route_id);
+                            break Err(HapiError::CoreError(error));
                         }
                         _ => {}
                     }
@@ -261,12 +437,12 @@ impl CoreClient {
         }
     }
 
-    pub async fn add_route(&mut self, route: Route) -> Result<(), HapiError> {
-        // TODO: change return type to Result<Route, HapiError>
+    /// Adds the given route, returning the ids of the existing routes that completely shadow it
+    pub async fn add_route(&mut self, route: Route) -> Result<Vec<String>, HapiError> {
         let cmd_uuid = Uuid::new_v4();
         let command = AddRoute {
             id: cmd_uuid.to_string(),
-            route,
+            route: Box::new(route),
         };
         self.send_cmd.send(command)?;
 
@@ -275,9 +451,13 @@ impl CoreClient {
                 Ok(event) => {
                     log::debug!("Received event {:?}", event);
                     match event {
-                        RouteWasAdded { cmd_id, .. } => {
+                        RouteWasAdded {
+                            cmd_id,
+                            shadowed_by,
+                            ..
+                        } => {
                             if cmd_id == cmd_uuid.to_string() {
-                                break Ok(());
+                                break Ok(shadowed_by);
                             }
                         }
                         RouteWasNotAdded { cmd_id, error, .. } => {
@@ -330,6 +510,45 @@ impl CoreClient {
         }
     }
 
+    /// Changes the percentage of requests the given route sends to its canary upstreams,
+    /// returning the updated route
+    pub async fn set_traffic_split(
+        &mut self,
+        route_id: &str,
+        percentage: u8,
+    ) -> Result<Route, HapiError> {
+        let cmd_uuid = Uuid::new_v4();
+        let command = SetTrafficSplit {
+            id: cmd_uuid.to_string(),
+            route_id: route_id.to_string(),
+            percentage,
+        };
+        self.send_cmd.send(command)?;
+
+        loop {
+            match self.recv_evt.recv().await {
+                Ok(event) => {
+                    log::debug!("Received event {:?}", event);
+                    match event {
+                        TrafficSplitWasSet { cmd_id, route } if cmd_id == cmd_uuid.to_string() => {
+                            break Ok(route);
+                        }
+                        TrafficSplitWasNotSet { cmd_id, error }
+                            if cmd_id == cmd_uuid.to_string() =>
+                        {
+                            break Err(HapiError::CoreError(error));
+                        }
+                        _ => {}
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Error receiving message {:?}", error);
+                    break Err(HapiError::MessageReceiveError(error));
+                }
+            }
+        }
+    }
+
     pub async fn get_upstreams(&mut self) -> Result<Vec<UpstreamAddress>, HapiError> {
         let cmd_uuid = Uuid::new_v4();
         let command = LookupAllUpstreams {
@@ -365,9 +584,62 @@ fn load_json_file_db(db: JsonFile, send_evt: Sender<Event>) -> Result<Context, H
         for route in db.routes.unwrap().iter() {
             let r = Route::from(route.clone());
             context.add_route(r.clone())?;
-            let event = RouteWasAdded { cmd_id: String::from("init_event"), route: r.clone() };
+            let shadowed_by = shadowing_routes_for(&context, &r);
+            let event = RouteWasAdded {
+                cmd_id: String::from("init_event"),
+                route: r.clone(),
+                shadowed_by,
+            };
             send_evt.send(event)?;
         }
     }
     Ok(context)
 }
+
+fn shadowing_routes_for(context: &Context, route: &Route) -> Vec<String> {
+    let shadowed_by = context
+        .find_shadowing_routes(route.id.as_str())
+        .unwrap_or_default();
+    if !shadowed_by.is_empty() {
+        log::warn!(
+            "Route {} is completely shadowed by route(s) {:?}",
+            route.id,
+            shadowed_by
+        );
+    }
+    shadowed_by
+}
+
+fn send_tier_changes(send_evt: &Sender<Event>, tier_changes: Vec<TierChange>) {
+    for change in tier_changes {
+        let event = match change.to_tier {
+            Some(to_tier) if change.is_failback() => {
+                log::info!(
+                    "Route {} failed back from tier {:?} to tier {}",
+                    change.route_id,
+                    change.from_tier,
+                    to_tier
+                );
+                RouteWasFailedBack {
+                    route_id: change.route_id,
+                    to_tier,
+                }
+            }
+            _ => {
+                log::warn!(
+                    "Route {} failed over from tier {:?} to tier {:?}",
+                    change.route_id,
+                    change.from_tier,
+                    change.to_tier
+                );
+                RouteWasFailedOver {
+                    route_id: change.route_id,
+                    to_tier: change.to_tier,
+                }
+            }
+        };
+        if let Err(e) = send_evt.send(event) {
+            log::error!("Error sending event {}", e);
+        }
+    }
+}
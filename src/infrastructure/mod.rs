@@ -1,6 +1,9 @@
+pub(crate) mod breaker_handler;
 pub(crate) mod core_handler;
+pub(crate) mod outlier_handler;
 pub(crate) mod probe_handler;
 pub(crate) mod processor;
 pub(crate) mod serializable_model;
 pub(crate) mod settings;
 pub(crate) mod stats_handler;
+pub(crate) mod upstream_client;
@@ -0,0 +1,218 @@
+use crate::events::commands::Command;
+use crate::events::commands::Command::{CompleteRequest, EjectUpstream, RestoreUpstream};
+use crate::events::events::Event;
+use crate::events::events::Event::{RouteWasRemoved, UpstreamWasEjected};
+use crate::infrastructure::core_handler::CoreClient;
+use crate::infrastructure::settings::OutlierDetectionSettings;
+use crate::modules::core::upstream::UpstreamAddress;
+use crate::modules::outlier::{Ejection, OutlierDetector};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::time::{interval, sleep};
+use uuid::Uuid;
+
+/// Feeds the outlier detector of every route with the outcome of the requests forwarded to its
+/// upstreams, and periodically tells the core to eject the outliers. Ejected upstreams get
+/// restored once their ejection time is over, counted from when the core ejected them
+pub(crate) async fn handle_outliers(
+    mut recv_cmd: Receiver<Command>,
+    mut recv_evt: Receiver<Event>,
+    send_cmd: Sender<Command>,
+    send_evt: Sender<Event>,
+    settings: OutlierDetectionSettings,
+) {
+    let mut detections = interval(settings.interval());
+    let mut outlier_controller = OutlierController::build(send_cmd, send_evt, settings);
+
+    loop {
+        tokio::select! {
+            received = recv_cmd.recv() => match received {
+                Ok(command) => outlier_controller.handle(command),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Outlier detection skipped {} commands", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            received = recv_evt.recv() => match received {
+                Ok(event) => outlier_controller.handle_event(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Outlier detection skipped {} events", skipped);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = detections.tick() => outlier_controller.detect().await,
+        }
+    }
+}
+
+struct OutlierController {
+    detectors: HashMap<String, OutlierDetector>, // route id => detector of its upstreams
+    pending: HashMap<(String, UpstreamAddress), Duration>, // ejections the core didn't confirm yet
+    send_cmd: Sender<Command>,
+    send_evt: Sender<Event>,
+    settings: OutlierDetectionSettings,
+}
+
+impl OutlierController {
+    fn build(
+        send_cmd: Sender<Command>,
+        send_evt: Sender<Event>,
+        settings: OutlierDetectionSettings,
+    ) -> Self {
+        OutlierController {
+            detectors: HashMap::new(),
+            pending: HashMap::new(),
+            send_cmd,
+            send_evt,
+            settings,
+        }
+    }
+
+    fn handle(&mut self, command: Command) {
+        match command {
+            // abandoned requests tell nothing about the upstream
+            CompleteRequest {
+                route_id,
+                upstream_address,
+                latency,
+                failed,
+                ..
+            } if self.settings.enabled && (failed || latency.is_some()) => {
+                let settings = &self.settings;
+                self.detectors
+                    .entry(route_id)
+                    .or_insert_with(|| {
+                        OutlierDetector::build(
+                            settings.min_requests,
+                            settings.min_failure_rate,
+                            settings.failure_factor,
+                            settings.latency_factor,
+                            settings.base_ejection(),
+                            settings.max_ejection(),
+                            settings.max_ejection_percent,
+                        )
+                    })
+                    .record(&upstream_address, latency, failed);
+            }
+            RestoreUpstream {
+                route_id,
+                upstream_address,
+                ..
+            } => {
+                if let Some(detector) = self.detectors.get_mut(&route_id) {
+                    detector.restore(&upstream_address);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Upstreams only count as ejected once the core ejected them, since it rejects those that
+    /// are not part of the route anymore
+    fn handle_event(&mut self, event: Event) {
+        match event {
+            UpstreamWasEjected {
+                route_id,
+                upstream_address,
+            } => {
+                let key = (route_id, upstream_address);
+                if let Some(duration) = self.pending.remove(&key) {
+                    let (route_id, upstream_address) = key;
+                    if let Some(detector) = self.detectors.get_mut(&route_id) {
+                        detector.eject(&upstream_address);
+                    }
+                    schedule_restore(&self.send_cmd, route_id, upstream_address, duration);
+                }
+            }
+            RouteWasRemoved { route, .. } => {
+                self.detectors.remove(&route.id);
+                self.pending
+                    .retain(|(route_id, _), _| *route_id != route.id);
+            }
+            _ => {}
+        }
+    }
+
+    async fn detect(&mut self) {
+        if self.detectors.is_empty() {
+            return;
+        }
+        // the share of ejected upstreams is capped on the upstreams of the route, and not only
+        // those that got requests
+        let mut core_client = CoreClient::build(self.send_cmd.clone(), self.send_evt.subscribe());
+        let route_upstreams: HashMap<String, usize> = match core_client.get_routes().await {
+            Ok(routes) => routes
+                .iter()
+                .map(|route| {
+                    let addresses: HashSet<&UpstreamAddress> =
+                        route.upstreams().into_iter().map(|u| &u.address).collect();
+                    (route.id.clone(), addresses.len())
+                })
+                .collect(),
+            Err(e) => {
+                log::error!("Could not look up routes to detect outliers: {}", e);
+                return;
+            }
+        };
+        self.detectors
+            .retain(|route_id, _| route_upstreams.contains_key(route_id));
+
+        for (route_id, detector) in self.detectors.iter_mut() {
+            for ejection in detector.detect(route_upstreams[route_id]) {
+                log::warn!(
+                    "Ejecting outlier upstream {} from route {} for {:?}",
+                    ejection.upstream_address,
+                    route_id,
+                    ejection.duration
+                );
+                let key = (route_id.clone(), ejection.upstream_address.clone());
+                self.pending.insert(key, ejection.duration);
+                eject(&self.send_cmd, route_id, ejection);
+            }
+        }
+    }
+}
+
+/// Sends the command to eject an upstream from a route
+fn eject(send_cmd: &Sender<Command>, route_id: &str, ejection: Ejection) {
+    let command = EjectUpstream {
+        id: Uuid::new_v4().to_string(),
+        route_id: route_id.to_string(),
+        upstream_address: ejection.upstream_address,
+        duration: ejection.duration,
+    };
+    send_command(send_cmd, command);
+}
+
+/// Sends the command to restore an ejected upstream to its route once the ejection time is over
+fn schedule_restore(
+    send_cmd: &Sender<Command>,
+    route_id: String,
+    upstream_address: UpstreamAddress,
+    duration: Duration,
+) {
+    let send_cmd = send_cmd.clone();
+    tokio::spawn(async move {
+        sleep(duration).await;
+        log::info!(
+            "Restoring upstream {} to route {}",
+            upstream_address,
+            route_id
+        );
+        let command = RestoreUpstream {
+            id: Uuid::new_v4().to_string(),
+            route_id,
+            upstream_address,
+        };
+        send_command(&send_cmd, command);
+    });
+}
+
+fn send_command(send_cmd: &Sender<Command>, command: Command) {
+    match send_cmd.send(command) {
+        Ok(_) => log::debug!("Command sent"),
+        Err(e) => log::error!("Error sending command {}", e),
+    }
+}
@@ -1,64 +1,1739 @@
+use std::convert::Infallible;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use hyper::header::HOST;
-use hyper::{Body, Client, HeaderMap, Request, Response, Uri};
+use hyper::body::HttpBody;
+use hyper::header::{
+    HeaderName, HeaderValue, CONNECTION, CONTENT_TYPE, COOKIE, FORWARDED, HOST, PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION, SET_COOKIE, TE, TRAILER, TRANSFER_ENCODING, UPGRADE, VIA,
+};
+use hyper::http::request::Parts;
+use hyper::http::uri::InvalidUri;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode, Uri, Version};
 use tokio::sync::broadcast::{Receiver, Sender};
+use uuid::Uuid;
 
 use crate::events::commands::Command;
 use crate::events::events::Event;
 use crate::infrastructure::core_handler::CoreClient;
+use crate::infrastructure::settings::{
+    ErrorResponseSettings, ForwardingSettings, HapiSettings, TimeoutSettings,
+};
+use crate::infrastructure::upstream_client::{UpstreamClient, UpstreamClients};
+use crate::modules::core::context::{CoreError, LookupRequest, UpstreamMatch};
+use crate::modules::core::route::{RetryOn, UpstreamTimeouts};
 use crate::modules::core::upstream::UpstreamAddress;
+use crate::modules::stats::Outcome;
 use crate::HapiError;
 
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const PROTO: &str = "http"; // Hapi only listens for plain HTTP
+const KEEP_ALIVE: &str = "keep-alive";
+const PROXY_CONNECTION: &str = "proxy-connection"; // non-standard, but still sent by some clients
+const X_REQUEST_ID: &str = "x-request-id";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Forwards the given request to an upstream of its route. Every failure gets an error response
+/// instead, and every response carries the request ID, which is forwarded to the upstream too
 pub(crate) async fn process_request(
+    mut request: Request<Body>,
+    client: String,
+    peer: SocketAddr,
+    settings: Arc<HapiSettings>,
+    upstream_clients: UpstreamClients,
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<Response<Body>, Infallible> {
+    let request_id = request_id_for(&request);
+    let x_request_id = HeaderName::from_static(X_REQUEST_ID);
+    set_header(
+        request.headers_mut(),
+        x_request_id.clone(),
+        request_id.clone(),
+    );
+
+    let result = forward_request(
+        request,
+        client,
+        peer,
+        settings.clone(),
+        upstream_clients,
+        send_cmd,
+        recv_evt,
+    )
+    .await;
+    let mut response = result.unwrap_or_else(|error| {
+        log::warn!("Request {} failed: {}", request_id, error);
+        error_response(&error, &request_id, &settings.error_responses)
+    });
+    set_header(response.headers_mut(), x_request_id, request_id);
+    Ok(response)
+}
+
+async fn forward_request(
     request: Request<Body>,
     client: String,
+    peer: SocketAddr,
+    settings: Arc<HapiSettings>,
+    upstream_clients: UpstreamClients,
     send_cmd: Sender<Command>,
     recv_evt: Receiver<Event>,
-) -> Result<Response<Body>, HapiError> {
-    let method = request.method();
-    let path = request.uri().path();
+) -> Result<Response<Body>, ProxyError> {
+    let query = request.uri().query().map(|query| query.to_string());
+    let (parts, body) = request.into_parts();
+    let request = Request::from_parts(parts, ());
+    let mut lookup_request = lookup_request_for(&request);
 
-    let mut core_client = CoreClient::build(send_cmd, recv_evt);
-    // TODO: remove the following unwrap
+    let mut core_client = CoreClient::build(send_cmd.clone(), recv_evt);
     let maybe_upstream = core_client
-        .search_upstream(client.as_str(), path, method.as_str())
-        .await
-        .unwrap();
-    match maybe_upstream {
-        Some(upstream_address) => {
-            let upstream_uri = Uri::from_str(absolute_url_for(&upstream_address, path).as_str())?;
-            let headers = headers_for(&request, &upstream_address);
+        .search_upstream(client.as_str(), lookup_request.clone())
+        .await?;
+    let mut upstream = match maybe_upstream {
+        Some(upstream) => upstream,
+        None => {
+            log::debug!("No routes found for {:?}", request);
+            return Err(ProxyError::RouteNotMatched);
+        }
+    };
 
-            let mut upstream_request = Request::from(request);
-            *upstream_request.uri_mut() = upstream_uri;
-            *upstream_request.headers_mut() = headers;
-            log::debug!("Generated: {:?}", &upstream_request);
+    let retry = upstream
+        .retry
+        .clone()
+        .filter(|policy| policy.allows(request.method().as_str()));
+    // the body can only be read once, so it is buffered when it may be sent more than once. Bodies
+    // of unknown length or above the limit are streamed instead, without mirroring nor retries
+    let bufferable = body
+        .size_hint()
+        .exact()
+        .is_some_and(|length| length <= settings.body_buffer.max_bytes);
+    if upstream.mirror.is_some() && !bufferable {
+        log::debug!("Not mirroring request to route {}", upstream.route_id);
+    }
+    let max_attempts = match retry.as_ref() {
+        Some(_) if !bufferable => {
+            log::debug!("Not retrying request to route {}", upstream.route_id);
+            1
+        }
+        Some(policy) => policy.max_attempts.max(1),
+        None => 1,
+    };
+    // the lookup already counted the request as in flight, so it must be completed even if the
+    // client fails while its body is being buffered
+    let mut pending_completion = Some(RequestCompletion::build(send_cmd.clone(), &upstream));
+    let mut body = Some(body);
+    let buffered_body = if bufferable && (upstream.mirror.is_some() || max_attempts > 1) {
+        let bytes = hyper::body::to_bytes(body.take().unwrap_or_default()).await;
+        Some(bytes.map_err(HapiError::from)?)
+    } else {
+        None
+    };
 
-            let client = Client::new();
-            let response = client.request(upstream_request).await?;
+    let mut attempt = 1;
+    loop {
+        log::debug!(
+            "Matched route {} with params {:?} (attempt {})",
+            upstream.route_id,
+            upstream.params,
+            attempt
+        );
+        let upstream_address = upstream.upstream_address.clone();
+        let mut completion = pending_completion
+            .take()
+            .unwrap_or_else(|| RequestCompletion::build(send_cmd.clone(), &upstream));
+        let upstream_url =
+            absolute_url_for(&upstream_address, &upstream.upstream_path, query.as_deref());
+        let upstream_body = match &buffered_body {
+            Some(bytes) => Body::from(bytes.clone()),
+            None => body.take().unwrap_or_default(),
+        };
+        let mut upstream_request = Request::new(upstream_body);
+        *upstream_request.method_mut() = request.method().clone();
+        *upstream_request.uri_mut() =
+            Uri::from_str(upstream_url.as_str()).map_err(HapiError::from)?;
+        *upstream_request.version_mut() = request.version();
+        *upstream_request.headers_mut() =
+            headers_for(&request, &upstream_address, &peer, &settings.forwarding);
+        if let Some(name) = upstream.sticky_cookie.as_deref() {
+            remove_cookie(upstream_request.headers_mut(), name);
+        }
+        if let (Some(mirror), Some(bytes), 1) = (&upstream.mirror, &buffered_body, attempt) {
+            let (parts, body) = upstream_request.into_parts();
+            match mirror_request_for(&parts, mirror, Body::from(bytes.clone())) {
+                Ok(mirror_request) => {
+                    let mirror_client =
+                        upstream_clients.with_connect_timeout(settings.timeouts.connect());
+                    send_mirror(
+                        mirror_request,
+                        upstream.route_id.clone(),
+                        mirror.clone(),
+                        mirror_client,
+                        send_cmd.clone(),
+                    )
+                }
+                Err(e) => log::warn!("Unable to mirror request to {}: {}", mirror, e),
+            }
+            upstream_request = Request::from_parts(parts, body);
+        }
+        log::debug!("Generated: {:?}", &upstream_request);
+
+        let deadlines = Deadlines::of(&upstream.timeouts, &settings.timeouts);
+        let upstream_client = upstream_clients.with_connect_timeout(deadlines.connect);
+        let started = Instant::now();
+        let head_timeout = deadlines.first_byte.min(deadlines.total);
+        let request_future = upstream_client.request(upstream_request);
+        // a failed attempt is either a hyper error, or `None` when the upstream timed out
+        let result = match tokio::time::timeout(head_timeout, request_future).await {
+            Ok(Ok(response)) => {
+                completion.latency = Some(started.elapsed());
+                completion.failed = response.status().is_server_error();
+                Ok(response)
+            }
+            Ok(Err(e)) if !is_timeout(&e) => {
+                completion.failed = true;
+                Err(Some(e))
+            }
+            _ => {
+                completion.failed = true;
+                log::warn!("Upstream {} timed out", upstream_address);
+                count_outcome(
+                    &send_cmd,
+                    upstream.route_id.clone(),
+                    upstream_address.clone(),
+                    Outcome::UpstreamTimedOut,
+                );
+                Err(None)
+            }
+        };
+        let mut completion = Some(completion);
 
-            log::debug!("Response: {:?}", &response);
-            Ok(response)
+        if let Some(policy) = retry.as_ref().filter(|_| attempt < max_attempts) {
+            let should_retry = match &result {
+                Ok(response) => policy.retries_status(response.status().as_u16()),
+                Err(Some(e)) => e.is_connect() && policy.retry_on.contains(&RetryOn::ConnectError),
+                Err(None) => policy.retry_on.contains(&RetryOn::GatewayTimeout),
+            };
+            if should_retry {
+                completion = None; // reported before looking for an alternate upstream
+                log::warn!("Upstream {} failed attempt {}", upstream_address, attempt);
+                count_outcome(
+                    &send_cmd,
+                    upstream.route_id.clone(),
+                    upstream_address.clone(),
+                    Outcome::UpstreamRetried,
+                );
+                lookup_request
+                    .excluded_upstreams
+                    .push(upstream_address.clone());
+                tokio::time::sleep(policy.backoff_before(attempt)).await;
+                let alternate = match core_client
+                    .search_upstream(client.as_str(), lookup_request.clone())
+                    .await
+                {
+                    Err(HapiError::CoreError(CoreError::NoHealthyUpstream(_))) => None,
+                    result => result?,
+                };
+                if let Some(alternate) = alternate {
+                    upstream = alternate;
+                    attempt += 1;
+                    continue;
+                }
+                log::warn!("No alternate upstream left for route {}", upstream.route_id);
+            }
         }
-        None => {
-            log::debug!("No routes found for {:?}", request);
-            let response = Response::builder().status(404).body(Body::empty()).unwrap();
-            Ok(response)
+
+        let response = match result {
+            Ok(response) => response,
+            Err(Some(e)) => return Err(ProxyError::UpstreamUnreachable(e)),
+            Err(None) => return Err(ProxyError::UpstreamTimedOut),
+        };
+        let (parts, body) = response.into_parts();
+        let deadline = tokio::time::Instant::from_std(started + deadlines.total);
+        let (route_id, address) = (upstream.route_id.clone(), upstream_address.clone());
+        let send_cmd = send_cmd.clone();
+        let body = body_with_deadline(body, deadline, completion, move || {
+            log::warn!("Upstream {} timed out sending the response body", address);
+            count_outcome(&send_cmd, route_id, address, Outcome::UpstreamTimedOut);
+        });
+        let mut response = Response::from_parts(parts, body);
+        remove_hop_by_hop_headers(response.headers_mut());
+        if let Some((name, token)) = upstream.affinity_cookie {
+            let cookie = format!("{}={}; Path=/; HttpOnly", name, token);
+            match cookie.parse() {
+                Ok(value) => {
+                    response.headers_mut().append(SET_COOKIE, value);
+                }
+                Err(e) => log::warn!("Invalid affinity cookie {}: {}", cookie, e),
+            }
+        }
+
+        log::debug!("Response: {:?}", &response);
+        break Ok(response);
+    }
+}
+
+/// Reports the request as completed to the core when dropped, without waiting for it, so
+/// in-flight counts stay accurate even if the upstream call fails or the client goes away. Once
+/// the upstream responds, it goes along with the response body, so the request is completed when
+/// the body ends. Connection errors, timeouts and 5xx responses count as failures for the circuit
+/// breaker
+struct RequestCompletion {
+    send_cmd: Sender<Command>,
+    route_id: String,
+    upstream_address: UpstreamAddress,
+    latency: Option<Duration>, // time the upstream took to send the response head, if it did
+    failed: bool,
+}
+
+impl RequestCompletion {
+    fn build(send_cmd: Sender<Command>, upstream: &UpstreamMatch) -> Self {
+        RequestCompletion {
+            send_cmd,
+            route_id: upstream.route_id.clone(),
+            upstream_address: upstream.upstream_address.clone(),
+            latency: None,
+            failed: false,
         }
     }
 }
 
-fn absolute_url_for(upstream: &UpstreamAddress, original_path: &str) -> String {
+impl Drop for RequestCompletion {
+    fn drop(&mut self) {
+        let command = Command::CompleteRequest {
+            id: Uuid::new_v4().to_string(),
+            route_id: self.route_id.clone(),
+            upstream_address: self.upstream_address.clone(),
+            latency: self.latency,
+            failed: self.failed,
+        };
+        if let Err(e) = self.send_cmd.send(command) {
+            log::error!(
+                "Could not complete request to {}: {}",
+                self.upstream_address,
+                e
+            );
+        }
+    }
+}
+
+/// Sends the given request to the mirror upstream in the background and counts its outcome in
+/// stats. The response is discarded, and neither its failure nor its latency affect the client
+fn send_mirror(
+    request: Request<Body>,
+    route_id: String,
+    mirror: UpstreamAddress,
+    client: UpstreamClient,
+    send_cmd: Sender<Command>,
+) {
+    tokio::spawn(async move {
+        let outcome = match tokio::time::timeout(MIRROR_TIMEOUT, client.request(request)).await {
+            Ok(Ok(response)) if !response.status().is_server_error() => Outcome::MirrorSucceeded,
+            Ok(Ok(response)) => {
+                log::debug!("Mirror {} responded {}", mirror, response.status());
+                Outcome::MirrorFailed
+            }
+            Ok(Err(e)) => {
+                log::debug!("Error mirroring request to {}: {}", mirror, e);
+                Outcome::MirrorFailed
+            }
+            Err(_) => {
+                log::debug!("Mirror {} timed out", mirror);
+                Outcome::MirrorFailed
+            }
+        };
+
+        count_outcome(&send_cmd, route_id, mirror, outcome);
+    });
+}
+
+/// Counts the given outcome in stats, without waiting for it
+fn count_outcome(
+    send_cmd: &Sender<Command>,
+    route_id: String,
+    upstream_address: UpstreamAddress,
+    outcome: Outcome,
+) {
+    let command = Command::CountOutcome {
+        id: Uuid::new_v4().to_string(),
+        route_id,
+        upstream_address,
+        outcome,
+    };
+    if let Err(e) = send_cmd.send(command) {
+        log::warn!("Error counting outcome {}", e);
+    }
+}
+
+/// Upstream deadlines of a request: those of its route, or else the defaults in the settings
+struct Deadlines {
+    connect: Duration,
+    first_byte: Duration,
+    total: Duration,
+}
+
+impl Deadlines {
+    fn of(route_timeouts: &UpstreamTimeouts, defaults: &TimeoutSettings) -> Self {
+        Deadlines {
+            connect: route_timeouts.connect.unwrap_or_else(|| defaults.connect()),
+            first_byte: route_timeouts
+                .first_byte
+                .unwrap_or_else(|| defaults.first_byte()),
+            total: route_timeouts.total.unwrap_or_else(|| defaults.total()),
+        }
+    }
+}
+
+/// Connect timeouts surface as I/O errors somewhere down the chain of error sources
+fn is_timeout(error: &hyper::Error) -> bool {
+    let mut source = error.source();
+    while let Some(cause) = source {
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            if io_error.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+        source = cause.source();
+    }
+    false
+}
+
+/// Fails the given body once the deadline passes, so a slow upstream can't hold the client
+/// connection forever. The response head is already sent by then, so the client sees an aborted
+/// response. The request is completed once the body ends or gets dropped, and counts as failed if
+/// the deadline passed first
+fn body_with_deadline<F>(
+    body: Body,
+    deadline: tokio::time::Instant,
+    completion: Option<RequestCompletion>,
+    on_timeout: F,
+) -> Body
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stream = futures_util::stream::unfold(
+        (Some(body), Some(on_timeout), completion),
+        move |(body, on_timeout, mut completion)| async move {
+            let mut body = body?;
+            match tokio::time::timeout_at(deadline, body.data()).await {
+                Ok(Some(chunk)) => {
+                    let chunk = chunk.map_err(|e| Box::new(e) as Box<dyn Error + Send + Sync>);
+                    Some((chunk, (Some(body), on_timeout, completion)))
+                }
+                Ok(None) => None,
+                Err(_) => {
+                    if let Some(on_timeout) = on_timeout {
+                        on_timeout();
+                    }
+                    if let Some(completion) = completion.as_mut() {
+                        completion.failed = true;
+                    }
+                    let error =
+                        std::io::Error::new(std::io::ErrorKind::TimedOut, "deadline passed");
+                    Some((
+                        Err(Box::new(error) as Box<dyn Error + Send + Sync>),
+                        (None, None, completion),
+                    ))
+                }
+            }
+        },
+    );
+    Body::wrap_stream(stream)
+}
+
+/// Failures forwarding a request, each of them answered with its own status code
+#[derive(Debug)]
+enum ProxyError {
+    RouteNotMatched,
+    NoHealthyUpstream(String),         // id of the matched route
+    UpstreamUnreachable(hyper::Error), // the upstream refused the connection, or dropped it
+    UpstreamTimedOut,
+    Internal(HapiError), // such as the core not answering on the bus
+}
+
+impl ProxyError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::RouteNotMatched => StatusCode::NOT_FOUND,
+            ProxyError::NoHealthyUpstream(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::UpstreamUnreachable(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::UpstreamTimedOut => StatusCode::GATEWAY_TIMEOUT,
+            ProxyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What the client is told, which leaves out the details of the error
+    fn message(&self) -> &'static str {
+        match self {
+            ProxyError::RouteNotMatched => "No route matches the request",
+            ProxyError::NoHealthyUpstream(_) => "No upstream is available to serve the request",
+            ProxyError::UpstreamUnreachable(_) => "The upstream could not be reached",
+            ProxyError::UpstreamTimedOut => "The upstream did not respond in time",
+            ProxyError::Internal(_) => "The request could not be processed",
+        }
+    }
+}
+
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProxyError::NoHealthyUpstream(route_id) => {
+                write!(f, "No healthy upstream for route {}", route_id)
+            }
+            ProxyError::UpstreamUnreachable(hyper_error) => write!(f, "{:?}", hyper_error),
+            ProxyError::Internal(hapi_error) => write!(f, "{}", hapi_error),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl From<HapiError> for ProxyError {
+    fn from(hapi_error: HapiError) -> Self {
+        match hapi_error {
+            HapiError::CoreError(CoreError::NoHealthyUpstream(route_id)) => {
+                ProxyError::NoHealthyUpstream(route_id)
+            }
+            hapi_error => ProxyError::Internal(hapi_error),
+        }
+    }
+}
+
+fn error_response(
+    error: &ProxyError,
+    request_id: &str,
+    settings: &ErrorResponseSettings,
+) -> Response<Body> {
+    let status = error.status();
+    let reason = status.canonical_reason().unwrap_or_default();
+    let (content_type, body) =
+        settings.render(status.as_u16(), reason, error.message(), request_id);
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+/// The request ID sent by the client is kept, as long as it is short and safe to render in error
+/// responses. Otherwise, a new one is generated
+fn request_id_for<B>(request: &Request<B>) -> String {
+    request
+        .headers()
+        .get(X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH)
+        .filter(|id| {
+            id.chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        })
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+fn mirror_request_for(
+    parts: &Parts,
+    mirror: &UpstreamAddress,
+    body: Body,
+) -> Result<Request<Body>, InvalidUri> {
+    let mut request = Request::new(body);
+    *request.method_mut() = parts.method.clone();
+    let url = absolute_url_for(mirror, parts.uri.path(), parts.uri.query());
+    *request.uri_mut() = Uri::from_str(url.as_str())?;
+    *request.version_mut() = parts.version;
+    *request.headers_mut() = parts.headers.clone();
+    if let Ok(host) = mirror.to_string().parse() {
+        request.headers_mut().insert(HOST, host);
+    }
+    Ok(request)
+}
+
+fn lookup_request_for<B>(request: &Request<B>) -> LookupRequest {
+    let mut lookup_request = LookupRequest::build(request.uri().path(), request.method().as_str());
+    lookup_request.host = host_for(request).map(|h| h.to_string());
+    lookup_request.headers = request
+        .headers()
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = value.to_str().ok()?;
+            Some((name.as_str().to_string(), value.to_string()))
+        })
+        .collect();
+    lookup_request.query = request
+        .uri()
+        .query()
+        .map(|q| form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+        .unwrap_or_default();
+    lookup_request
+}
+
+/// The request authority takes precedence over the Host header, as mandated by RFC 7230
+fn host_for<B>(request: &Request<B>) -> Option<&str> {
+    request
+        .uri()
+        .authority()
+        .map(|authority| authority.as_str())
+        .or_else(|| request.headers().get(HOST).and_then(|h| h.to_str().ok()))
+}
+
+/// The path and query are forwarded exactly as received, so their percent-encoding is preserved
+fn absolute_url_for(upstream: &UpstreamAddress, path: &str, query: Option<&str>) -> String {
     let mut absolute_url = String::from("http://");
     absolute_url.push_str(upstream.to_string().as_str());
-    absolute_url.push_str(original_path);
+    absolute_url.push_str(path);
+    if let Some(query) = query {
+        absolute_url.push('?');
+        absolute_url.push_str(query);
+    }
     absolute_url
 }
 
-fn headers_for(request: &Request<Body>, upstream: &UpstreamAddress) -> HeaderMap {
+fn headers_for<B>(
+    request: &Request<B>,
+    upstream: &UpstreamAddress,
+    peer: &SocketAddr,
+    settings: &ForwardingSettings,
+) -> HeaderMap {
     let original_headers = request.headers();
     let mut headers = original_headers.clone();
+    remove_hop_by_hop_headers(&mut headers);
+    add_forwarding_headers(&mut headers, request, peer, settings);
     headers.insert(HOST, upstream.to_string().parse().unwrap());
     headers
 }
+
+/// Removes the given cookie from the `Cookie` headers, such as the affinity cookie, which only
+/// means something to Hapi. The remaining cookies are forwarded in a single header
+fn remove_cookie(headers: &mut HeaderMap, name: &str) {
+    let is_named = |pair: &str| pair.split_once('=').is_some_and(|(n, _)| n.trim() == name);
+    let values: Vec<&str> = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let found = values
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .any(is_named);
+    if !found {
+        return;
+    }
+
+    let cookies: Vec<&str> = values
+        .iter()
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty() && !is_named(pair))
+        .collect();
+    let cookies = cookies.join("; ");
+    headers.remove(COOKIE);
+    if !cookies.is_empty() {
+        match cookies.parse() {
+            Ok(value) => {
+                headers.insert(COOKIE, value);
+            }
+            Err(e) => log::warn!("Invalid cookies {}: {}", cookies, e),
+        }
+    }
+}
+
+/// Removes the headers that only apply to a single connection, as mandated by RFC 7230: the
+/// standard hop-by-hop headers and any header named in `Connection`. Hyper frames the message
+/// again on the next connection
+fn remove_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let connection_headers: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in connection_headers {
+        headers.remove(name);
+    }
+
+    let hop_by_hop_headers = [
+        CONNECTION,
+        HeaderName::from_static(KEEP_ALIVE),
+        HeaderName::from_static(PROXY_CONNECTION),
+        PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION,
+        TE,
+        TRAILER,
+        TRANSFER_ENCODING,
+        UPGRADE,
+    ];
+    for name in hop_by_hop_headers {
+        headers.remove(name);
+    }
+}
+
+/// Tells the upstream about the original request: the client address (`X-Forwarded-For`), scheme
+/// (`X-Forwarded-Proto`) and host (`X-Forwarded-Host`), all of them in the RFC 7239 `Forwarded`
+/// header as well, and the proxies it went through (`Via`). Forwarded headers from trusted
+/// proxies are extended with this hop, from anyone else they are replaced
+fn add_forwarding_headers<B>(
+    headers: &mut HeaderMap,
+    request: &Request<B>,
+    peer: &SocketAddr,
+    settings: &ForwardingSettings,
+) {
+    let x_forwarded_for = HeaderName::from_static(X_FORWARDED_FOR);
+    let x_forwarded_proto = HeaderName::from_static(X_FORWARDED_PROTO);
+    let x_forwarded_host = HeaderName::from_static(X_FORWARDED_HOST);
+    if !settings.trusts(&peer.ip()) {
+        for name in [
+            &x_forwarded_for,
+            &x_forwarded_proto,
+            &x_forwarded_host,
+            &FORWARDED,
+        ] {
+            headers.remove(name);
+        }
+    }
+    let host = host_for(request).map(|host| host.to_string());
+
+    append_header(headers, x_forwarded_for, peer.ip().to_string());
+    if !headers.contains_key(&x_forwarded_proto) {
+        headers.insert(x_forwarded_proto, HeaderValue::from_static(PROTO));
+    }
+    if let Some(host) = &host {
+        if !headers.contains_key(&x_forwarded_host) {
+            set_header(headers, x_forwarded_host, host.clone());
+        }
+    }
+
+    let mut forwarded = format!("for={}", forwarded_node_for(peer));
+    if let Some(host) = &host {
+        forwarded.push_str(";host=");
+        forwarded.push_str(forwarded_value_for(host).as_str());
+    }
+    forwarded.push_str(";proto=");
+    forwarded.push_str(PROTO);
+    append_header(headers, FORWARDED, forwarded);
+
+    let via = format!(
+        "{} {}",
+        protocol_version_for(request.version()),
+        settings.via_pseudonym
+    );
+    append_header(headers, VIA, via);
+}
+
+/// Joins the given value to the current values of the header, as a comma separated list
+fn append_header(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    let mut values: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    values.push(value.as_str());
+    let joined = values.join(", ");
+    set_header(headers, name, joined);
+}
+
+fn set_header(headers: &mut HeaderMap, name: HeaderName, value: String) {
+    match HeaderValue::from_str(value.as_str()) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(e) => log::warn!("Invalid {} header value {}: {}", name, value, e),
+    }
+}
+
+/// IPv6 addresses must be bracketed and quoted in the `Forwarded` header
+fn forwarded_node_for(peer: &SocketAddr) -> String {
+    match peer {
+        SocketAddr::V4(address) => address.ip().to_string(),
+        SocketAddr::V6(address) => format!("\"[{}]\"", address.ip()),
+    }
+}
+
+/// Values that aren't RFC 7230 tokens, such as hosts with a port, must be quoted
+fn forwarded_value_for(value: &str) -> String {
+    let is_token = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+fn protocol_version_for(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use hyper::header::{
+        CONNECTION, CONTENT_TYPE, COOKIE, FORWARDED, HOST, PROXY_AUTHENTICATE, TRANSFER_ENCODING,
+        VIA,
+    };
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, HeaderMap, Request, Response, Server};
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpStream;
+    use tokio::sync::broadcast;
+    use tokio::sync::broadcast::{Receiver, Sender};
+    use tokio::sync::mpsc;
+
+    use crate::events::commands::Command;
+    use crate::events::events::Event;
+    use crate::infrastructure::processor::{
+        absolute_url_for, error_response, headers_for, process_request, remove_cookie,
+        remove_hop_by_hop_headers, ProxyError,
+    };
+    use crate::infrastructure::settings::{
+        ErrorFormat, ErrorResponseSettings, ForwardingMode, ForwardingSettings, HapiSettings,
+    };
+    use crate::infrastructure::upstream_client::{UpstreamClientSettings, UpstreamClients};
+    use crate::modules::core::route::{RetryPolicy, UpstreamTimeouts};
+    use crate::modules::core::upstream::UpstreamAddress;
+    use crate::modules::stats::Outcome;
+
+    const SETTINGS: &str = r#"{
+        "ip_address": "127.0.0.1",
+        "port": 3000,
+        "api_ip_address": "127.0.0.1",
+        "api_port": 3001
+    }"#;
+
+    #[test]
+    fn should_build_absolute_url_with_query() {
+        // given:
+        let upstream = UpstreamAddress::FQDN(String::from("localhost:8001"));
+
+        // when:
+        let result = absolute_url_for(&upstream, "/users", Some("name=a%20b&page=2"));
+
+        // then:
+        assert_eq!("http://localhost:8001/users?name=a%20b&page=2", result)
+    }
+
+    #[tokio::test]
+    async fn should_forward_path_and_query_to_upstream() {
+        // given:
+        let (upstream, mut received_uris, _) = stub_upstream().await;
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let uris = vec![
+            "/users",
+            "/users?page=2&size=10",
+            "/users?name=J%C3%B3se&tags=a%2Cb&empty=",
+            "/files/a%20b/c%2Fd?q=%3F%26%3D",
+            "/?flag",
+        ];
+
+        for uri in uris {
+            // when:
+            let response = proxy(uri, &upstream, &clients).await;
+
+            // then:
+            assert_eq!(200, response.status());
+            assert_eq!(Some(String::from(uri)), received_uris.recv().await);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_only_mirror_bodies_of_known_length() {
+        // given:
+        let (upstream, mut received_uris, _) = stub_upstream().await;
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let known_length = Request::builder()
+            .method("POST")
+            .uri("/mirrored")
+            .body(Body::from("payload"))
+            .unwrap();
+        let (mut sender, streamed_body) = Body::channel();
+        let streamed = Request::builder()
+            .method("POST")
+            .uri("/mirrored")
+            .body(streamed_body)
+            .unwrap();
+        tokio::spawn(async move { sender.send_data("payload".into()).await });
+
+        // when:
+        let (send_cmd, _) = broadcast::channel(16);
+        call_proxy(
+            known_length,
+            &[upstream],
+            &clients,
+            timeouts.clone(),
+            None,
+            send_cmd,
+        )
+        .await;
+        let mirrored = mirrored_requests(&mut received_uris).await;
+        let (send_cmd, _) = broadcast::channel(16);
+        call_proxy(streamed, &[upstream], &clients, timeouts, None, send_cmd).await;
+        let streamed = mirrored_requests(&mut received_uris).await;
+
+        // then:
+        assert_eq!(2, mirrored);
+        assert_eq!(1, streamed);
+    }
+
+    #[test]
+    fn should_remove_affinity_cookie() {
+        // given:
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            "session=abc; hapi=token; theme=dark".parse().unwrap(),
+        );
+        let mut only_affinity = HeaderMap::new();
+        only_affinity.insert(COOKIE, "hapi=token".parse().unwrap());
+        let mut without_affinity = HeaderMap::new();
+        without_affinity.append(COOKIE, "session=abc".parse().unwrap());
+        without_affinity.append(COOKIE, "hapi_other=1".parse().unwrap());
+
+        // when:
+        remove_cookie(&mut headers, "hapi");
+        remove_cookie(&mut only_affinity, "hapi");
+        remove_cookie(&mut without_affinity, "hapi");
+
+        // then:
+        assert_eq!("session=abc; theme=dark", headers[COOKIE]);
+        assert!(!only_affinity.contains_key(COOKIE));
+        assert_eq!(2, without_affinity.get_all(COOKIE).iter().count());
+    }
+
+    #[test]
+    fn should_replace_forwarding_headers_of_untrusted_peers() {
+        // given:
+        let request = Request::builder()
+            .uri("/users")
+            .header(HOST, "example.com:3000")
+            .header("X-Forwarded-For", "1.2.3.4")
+            .header("X-Forwarded-Proto", "https")
+            .header(FORWARDED, "for=1.2.3.4")
+            .header(VIA, "1.1 edge")
+            .body(Body::empty())
+            .unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 50000));
+        let upstream = UpstreamAddress::FQDN(String::from("localhost:8001"));
+
+        // when:
+        let headers = headers_for(&request, &upstream, &peer, &ForwardingSettings::default());
+
+        // then:
+        assert_eq!("localhost:8001", headers[HOST]);
+        assert_eq!("10.0.0.1", headers["X-Forwarded-For"]);
+        assert_eq!("http", headers["X-Forwarded-Proto"]);
+        assert_eq!("example.com:3000", headers["X-Forwarded-Host"]);
+        assert_eq!(
+            "for=10.0.0.1;host=\"example.com:3000\";proto=http",
+            headers[FORWARDED]
+        );
+        assert_eq!("1.1 edge, 1.1 hapi", headers[VIA]);
+    }
+
+    #[test]
+    fn should_extend_forwarding_headers_of_trusted_proxies() {
+        // given:
+        let request = Request::builder()
+            .uri("/users")
+            .header(HOST, "example.com")
+            .header("X-Forwarded-For", "1.2.3.4")
+            .header("X-Forwarded-For", "5.6.7.8")
+            .header("X-Forwarded-Proto", "https")
+            .header("X-Forwarded-Host", "www.example.com")
+            .header(FORWARDED, "for=1.2.3.4;proto=https")
+            .body(Body::empty())
+            .unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 50000));
+        let upstream = UpstreamAddress::FQDN(String::from("localhost:8001"));
+        let settings = ForwardingSettings {
+            trusted_proxies: vec![peer.ip()],
+            ..Default::default()
+        };
+
+        // when:
+        let headers = headers_for(&request, &upstream, &peer, &settings);
+
+        // then:
+        assert_eq!(1, headers.get_all("X-Forwarded-For").iter().count());
+        assert_eq!("1.2.3.4, 5.6.7.8, 10.0.0.1", headers["X-Forwarded-For"]);
+        assert_eq!("https", headers["X-Forwarded-Proto"]);
+        assert_eq!("www.example.com", headers["X-Forwarded-Host"]);
+        assert_eq!(
+            "for=1.2.3.4;proto=https, for=10.0.0.1;host=example.com;proto=http",
+            headers[FORWARDED]
+        );
+        assert_eq!("1.1 hapi", headers[VIA]);
+    }
+
+    #[test]
+    fn should_overwrite_forwarding_headers_of_trusted_proxies() {
+        // given:
+        let request = Request::builder()
+            .uri("/users")
+            .header("X-Forwarded-For", "1.2.3.4")
+            .header(FORWARDED, "for=1.2.3.4")
+            .body(Body::empty())
+            .unwrap();
+        let peer: SocketAddr = "[2001:db8::1]:50000".parse().unwrap();
+        let upstream = UpstreamAddress::FQDN(String::from("localhost:8001"));
+        let settings = ForwardingSettings {
+            mode: ForwardingMode::Overwrite,
+            trusted_proxies: vec![peer.ip()],
+            via_pseudonym: String::from("gateway"),
+        };
+
+        // when:
+        let headers = headers_for(&request, &upstream, &peer, &settings);
+
+        // then:
+        assert_eq!("2001:db8::1", headers["X-Forwarded-For"]);
+        assert_eq!(None, headers.get("X-Forwarded-Host"));
+        assert_eq!("for=\"[2001:db8::1]\";proto=http", headers[FORWARDED]);
+        assert_eq!("1.1 gateway", headers[VIA]);
+    }
+
+    #[test]
+    fn should_remove_hop_by_hop_request_headers() {
+        // given:
+        let request = Request::builder()
+            .uri("/users")
+            .header(CONNECTION, "keep-alive, X-Session")
+            .header(CONNECTION, "Upgrade")
+            .header("Keep-Alive", "timeout=5")
+            .header("X-Session", "secret")
+            .header("Upgrade", "websocket")
+            .header("TE", "trailers")
+            .header("Proxy-Authorization", "Basic aGFwaTpoYXBp")
+            .header("Proxy-Connection", "keep-alive")
+            .header(TRANSFER_ENCODING, "chunked")
+            .header("Authorization", "Bearer token")
+            .body(Body::empty())
+            .unwrap();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 50000));
+        let upstream = UpstreamAddress::FQDN(String::from("localhost:8001"));
+
+        // when:
+        let headers = headers_for(&request, &upstream, &peer, &ForwardingSettings::default());
+
+        // then:
+        for name in [
+            "Connection",
+            "Keep-Alive",
+            "X-Session",
+            "Upgrade",
+            "TE",
+            "Proxy-Authorization",
+            "Proxy-Connection",
+            "Transfer-Encoding",
+        ] {
+            assert_eq!(None, headers.get(name), "{} was forwarded", name);
+        }
+        assert_eq!("Bearer token", headers["Authorization"]);
+        assert_eq!("localhost:8001", headers[HOST]);
+    }
+
+    #[test]
+    fn should_remove_hop_by_hop_response_headers() {
+        // given:
+        let mut response = Response::builder()
+            .header(CONNECTION, "close, X-Upstream-Debug")
+            .header("X-Upstream-Debug", "on")
+            .header("Keep-Alive", "timeout=5")
+            .header(PROXY_AUTHENTICATE, "Basic")
+            .header("Trailer", "Expires")
+            .header(TRANSFER_ENCODING, "chunked")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+
+        // when:
+        remove_hop_by_hop_headers(response.headers_mut());
+
+        // then:
+        let names: Vec<&str> = response
+            .headers()
+            .keys()
+            .map(|name| name.as_str())
+            .collect();
+        assert_eq!(vec!["content-type"], names);
+    }
+
+    #[tokio::test]
+    async fn should_reuse_upstream_connections() {
+        // given:
+        let (upstream, _received_uris, connections) = stub_upstream().await;
+        let pooled_clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let unpooled_clients = UpstreamClients::build(&UpstreamClientSettings {
+            keep_alive: false,
+            ..Default::default()
+        });
+
+        // when:
+        for _ in 0..3 {
+            proxy("/users", &upstream, &pooled_clients).await;
+        }
+        let pooled_connections = connections.load(Ordering::SeqCst);
+        for _ in 0..3 {
+            proxy("/users", &upstream, &unpooled_clients).await;
+        }
+        let unpooled_connections = connections.load(Ordering::SeqCst) - pooled_connections;
+
+        // then:
+        assert_eq!(1, pooled_connections);
+        assert_eq!(3, unpooled_connections);
+    }
+
+    #[tokio::test]
+    async fn should_time_out_waiting_for_response_head() {
+        // given:
+        let upstream = slow_upstream(Duration::from_millis(500), Duration::ZERO).await;
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts {
+            first_byte: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        // when:
+        let (response, commands) = proxy_with_timeouts(&upstream, &clients, timeouts).await;
+
+        // then:
+        assert_eq!(504, response.status());
+        assert_eq!(Outcome::UpstreamTimedOut, counted_outcome(commands).await);
+    }
+
+    #[tokio::test]
+    async fn should_time_out_waiting_for_response_body() {
+        // given:
+        let upstream = slow_upstream(Duration::ZERO, Duration::from_millis(500)).await;
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts {
+            total: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+
+        // when:
+        let (response, commands) = proxy_with_timeouts(&upstream, &clients, timeouts).await;
+        let body = hyper::body::to_bytes(response.into_body()).await;
+
+        // then:
+        assert!(body.is_err());
+        assert_eq!(Outcome::UpstreamTimedOut, counted_outcome(commands).await);
+    }
+
+    #[tokio::test]
+    async fn should_not_time_out_within_deadlines() {
+        // given:
+        let upstream = slow_upstream(Duration::from_millis(20), Duration::from_millis(20)).await;
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts {
+            connect: Some(Duration::from_millis(500)),
+            first_byte: Some(Duration::from_millis(500)),
+            total: Some(Duration::from_millis(1000)),
+        };
+
+        // when:
+        let (response, _) = proxy_with_timeouts(&upstream, &clients, timeouts).await;
+        let body = hyper::body::to_bytes(response.into_body()).await;
+
+        // then:
+        assert_eq!("first, second", body.unwrap());
+    }
+
+    #[tokio::test]
+    async fn should_report_failed_requests() {
+        // given:
+        let failing = status_upstream(503).await;
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, mut commands) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[failing], &clients, timeouts, None, send_cmd).await;
+
+        // then:
+        assert_eq!(503, response.status());
+        drop(response);
+        loop {
+            let command = tokio::time::timeout(Duration::from_secs(1), commands.recv());
+            if let Command::CompleteRequest { failed, .. } = command.await.unwrap().unwrap() {
+                assert!(failed);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn should_complete_request_once_response_body_ends() {
+        // given:
+        let upstream = slow_upstream(Duration::ZERO, Duration::from_millis(100)).await;
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, mut commands) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[upstream], &clients, timeouts, None, send_cmd).await;
+        let completed_with_head = completed(&mut commands);
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let completed_with_body = completed(&mut commands);
+
+        // then:
+        assert!(!completed_with_head);
+        assert!(completed_with_body);
+    }
+
+    #[tokio::test]
+    async fn should_complete_request_if_client_fails_while_sending_body() {
+        // given:
+        let upstream = status_upstream(200).await;
+        let (send_cmd, mut commands) = broadcast::channel(16);
+        let proxy = proxy_server(upstream, Some(RetryPolicy::build(2)), send_cmd).await;
+
+        // when:
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = "PUT /users HTTP/1.1\r\nHost: hapi\r\nContent-Length: 100\r\n\r\npartial";
+        client.write_all(request.as_bytes()).await.unwrap();
+        drop(client);
+        let completion = loop {
+            let command = tokio::time::timeout(Duration::from_secs(1), commands.recv());
+            if let Command::CompleteRequest {
+                latency, failed, ..
+            } = command.await.unwrap().unwrap()
+            {
+                break (latency, failed);
+            }
+        };
+
+        // then:
+        assert_eq!((None, false), completion);
+    }
+
+    #[tokio::test]
+    async fn should_retry_on_alternate_upstream() {
+        // given:
+        let failing = status_upstream(503).await;
+        let healthy = status_upstream(200).await;
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+
+        // when:
+        let (response, commands) =
+            proxy_with_retries(request, &[failing, healthy], RetryPolicy::build(3)).await;
+
+        // then:
+        assert_eq!(200, response.status());
+        assert_eq!(Outcome::UpstreamRetried, counted_outcome(commands).await);
+    }
+
+    #[tokio::test]
+    async fn should_retry_on_connect_error() {
+        // given:
+        let closed = closed_upstream();
+        let healthy = status_upstream(200).await;
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+
+        // when:
+        let (response, _) =
+            proxy_with_retries(request, &[closed, healthy], RetryPolicy::build(2)).await;
+
+        // then:
+        assert_eq!(200, response.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_last_failure_without_alternate_upstream() {
+        // given:
+        let failing = status_upstream(502).await;
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+
+        // when:
+        let (response, _) = proxy_with_retries(request, &[failing], RetryPolicy::build(3)).await;
+
+        // then:
+        assert_eq!(502, response.status());
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_non_idempotent_requests() {
+        // given:
+        let failing = status_upstream(503).await;
+        let healthy = status_upstream(200).await;
+        let request = Request::builder()
+            .method("POST")
+            .uri("/users")
+            .body(Body::from("payload"))
+            .unwrap();
+
+        // when:
+        let (response, _) =
+            proxy_with_retries(request, &[failing, healthy], RetryPolicy::build(3)).await;
+
+        // then:
+        assert_eq!(503, response.status());
+    }
+
+    #[tokio::test]
+    async fn should_not_retry_streamed_bodies() {
+        // given:
+        let failing = status_upstream(503).await;
+        let healthy = status_upstream(200).await;
+        let (mut sender, streamed_body) = Body::channel();
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/users")
+            .body(streamed_body)
+            .unwrap();
+        tokio::spawn(async move { sender.send_data("payload".into()).await });
+
+        // when:
+        let (response, _) =
+            proxy_with_retries(request, &[failing, healthy], RetryPolicy::build(3)).await;
+
+        // then:
+        assert_eq!(503, response.status());
+    }
+
+    #[tokio::test]
+    async fn should_stream_whole_body_of_retried_routes() {
+        // given:
+        let upstream = echo_upstream().await;
+        let (mut sender, streamed_body) = Body::channel();
+        let request = Request::builder()
+            .method("PUT")
+            .uri("/users")
+            .body(streamed_body)
+            .unwrap();
+        tokio::spawn(async move {
+            sender.send_data("first, ".into()).await.unwrap();
+            sender.send_data("second".into()).await.unwrap();
+        });
+
+        // when:
+        let (response, _) = proxy_with_retries(request, &[upstream], RetryPolicy::build(3)).await;
+
+        // then:
+        assert_eq!(200, response.status());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!("first, second", body);
+    }
+
+    #[tokio::test]
+    async fn should_respond_bad_gateway_if_upstream_unreachable() {
+        // given:
+        let closed = closed_upstream();
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, _) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[closed], &clients, timeouts, None, send_cmd).await;
+
+        // then:
+        assert_eq!(502, response.status());
+        assert_eq!("application/json", response.headers()[CONTENT_TYPE]);
+        let request_id = response.headers()["x-request-id"]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(502, body["status"]);
+        assert_eq!(request_id, body["request_id"]);
+    }
+
+    #[tokio::test]
+    async fn should_respond_not_found_if_no_route_matches() {
+        // given:
+        let request = Request::builder()
+            .uri("/unknown")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, _) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[], &clients, timeouts, None, send_cmd).await;
+
+        // then:
+        assert_eq!(404, response.status());
+        assert!(response.headers().contains_key("x-request-id"));
+    }
+
+    #[tokio::test]
+    async fn should_respond_service_unavailable_without_healthy_upstream() {
+        // given:
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, _) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[], &clients, timeouts, None, send_cmd).await;
+
+        // then:
+        assert_eq!(503, response.status());
+    }
+
+    #[tokio::test]
+    async fn should_keep_request_id_sent_by_client() {
+        // given:
+        let upstream = status_upstream(200).await;
+        let request = Request::builder()
+            .uri("/users")
+            .header("x-request-id", "abc-123")
+            .body(Body::empty())
+            .unwrap();
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, _) = broadcast::channel(16);
+
+        // when:
+        let response = call_proxy(request, &[upstream], &clients, timeouts, None, send_cmd).await;
+
+        // then:
+        assert_eq!(200, response.status());
+        assert_eq!("abc-123", response.headers()["x-request-id"]);
+    }
+
+    #[tokio::test]
+    async fn should_render_html_error_responses() {
+        // given:
+        let settings = ErrorResponseSettings {
+            format: ErrorFormat::Html,
+            html_template: String::from("<p>{status} {reason}: {message} ({request_id})</p>"),
+            ..Default::default()
+        };
+
+        // when:
+        let result = error_response(&ProxyError::UpstreamTimedOut, "abc-123", &settings);
+
+        // then:
+        assert_eq!(504, result.status());
+        assert_eq!("text/html; charset=utf-8", result.headers()[CONTENT_TYPE]);
+        let body = hyper::body::to_bytes(result.into_body()).await;
+        assert_eq!(
+            "<p>504 Gateway Timeout: The upstream did not respond in time (abc-123)</p>",
+            body.unwrap()
+        );
+    }
+
+    async fn proxy(uri: &str, upstream: &SocketAddr, clients: &UpstreamClients) -> Response<Body> {
+        let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, _) = broadcast::channel(16);
+        call_proxy(request, &[*upstream], clients, timeouts, None, send_cmd).await
+    }
+
+    /// Proxies a request to the given upstream, returning the commands sent meanwhile
+    async fn proxy_with_timeouts(
+        upstream: &SocketAddr,
+        clients: &UpstreamClients,
+        timeouts: UpstreamTimeouts,
+    ) -> (Response<Body>, Receiver<Command>) {
+        let request = Request::builder()
+            .uri("/users")
+            .body(Body::empty())
+            .unwrap();
+        let (send_cmd, commands) = broadcast::channel(16);
+        let response = call_proxy(request, &[*upstream], clients, timeouts, None, send_cmd).await;
+        (response, commands)
+    }
+
+    /// Proxies the given request to the given upstreams, returning the commands sent meanwhile
+    async fn proxy_with_retries(
+        request: Request<Body>,
+        upstreams: &[SocketAddr],
+        retry: RetryPolicy,
+    ) -> (Response<Body>, Receiver<Command>) {
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let timeouts = UpstreamTimeouts::default();
+        let (send_cmd, commands) = broadcast::channel(16);
+        let response = call_proxy(
+            request,
+            upstreams,
+            &clients,
+            timeouts,
+            Some(retry),
+            send_cmd,
+        )
+        .await;
+        (response, commands)
+    }
+
+    async fn call_proxy(
+        request: Request<Body>,
+        upstreams: &[SocketAddr],
+        clients: &UpstreamClients,
+        timeouts: UpstreamTimeouts,
+        retry: Option<RetryPolicy>,
+        send_cmd: Sender<Command>,
+    ) -> Response<Body> {
+        let recv_cmd = send_cmd.subscribe();
+        let (send_evt, _) = broadcast::channel(16);
+        let recv_evt = send_evt.subscribe();
+        tokio::spawn(fake_core(
+            recv_cmd,
+            send_evt,
+            upstreams.to_vec(),
+            timeouts,
+            retry,
+        ));
+
+        let client = SocketAddr::from(([127, 0, 0, 1], 50000));
+        let settings: HapiSettings = serde_json::from_str(SETTINGS).unwrap();
+        process_request(
+            request,
+            client.ip().to_string(),
+            client,
+            Arc::new(settings),
+            clients.clone(),
+            send_cmd,
+            recv_evt,
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Returns the outcome counted in stats, if any
+    async fn counted_outcome(mut commands: Receiver<Command>) -> Outcome {
+        loop {
+            let command = tokio::time::timeout(Duration::from_secs(1), commands.recv());
+            if let Command::CountOutcome { outcome, .. } = command.await.unwrap().unwrap() {
+                break outcome;
+            }
+        }
+    }
+
+    /// Returns how many requests the upstream got, waiting a bit for mirrored ones
+    async fn mirrored_requests(received_uris: &mut mpsc::UnboundedReceiver<String>) -> usize {
+        let mut count = 0;
+        let wait = Duration::from_millis(200);
+        while let Ok(Some(_)) = tokio::time::timeout(wait, received_uris.recv()).await {
+            count += 1;
+        }
+        count
+    }
+
+    /// Starts a proxy in front of the given upstream, so requests come with a body read from
+    /// the connection
+    async fn proxy_server(
+        upstream: SocketAddr,
+        retry: Option<RetryPolicy>,
+        send_cmd: Sender<Command>,
+    ) -> SocketAddr {
+        let clients = UpstreamClients::build(&UpstreamClientSettings::default());
+        let make_service = make_service_fn(move |_conn| {
+            let (clients, retry, send_cmd) = (clients.clone(), retry.clone(), send_cmd.clone());
+            let service = service_fn(move |request: Request<Body>| {
+                let (clients, retry, send_cmd) = (clients.clone(), retry.clone(), send_cmd.clone());
+                async move {
+                    let timeouts = UpstreamTimeouts::default();
+                    let upstreams = [upstream];
+                    let response =
+                        call_proxy(request, &upstreams, &clients, timeouts, retry, send_cmd);
+                    Ok::<_, Infallible>(response.await)
+                }
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    /// Returns whether a request was completed, among the commands sent so far
+    fn completed(commands: &mut Receiver<Command>) -> bool {
+        let mut completed = false;
+        while let Ok(command) = commands.try_recv() {
+            completed |= matches!(command, Command::CompleteRequest { .. });
+        }
+        completed
+    }
+
+    /// Finds the first of the given upstreams that is not excluded, with the given timeouts and
+    /// retry policy, for every lookup of a path other than `/unknown`. Requests to `/mirrored` are
+    /// mirrored to that same upstream
+    async fn fake_core(
+        mut recv_cmd: Receiver<Command>,
+        send_evt: Sender<Event>,
+        upstreams: Vec<SocketAddr>,
+        timeouts: UpstreamTimeouts,
+        retry: Option<RetryPolicy>,
+    ) {
+        while let Ok(command) = recv_cmd.recv().await {
+            if let Command::LookupUpstream {
+                id,
+                client,
+                path,
+                method,
+                excluded_upstreams,
+                ..
+            } = command
+            {
+                let upstream = upstreams
+                    .iter()
+                    .map(address_of)
+                    .find(|address| !excluded_upstreams.contains(address));
+                let mirrored = path == "/mirrored";
+                let event = match upstream {
+                    _ if path == "/unknown" => Event::RouteWasNotMatched { cmd_id: id },
+                    Some(upstream_address) => Event::UpstreamWasFound {
+                        cmd_id: id,
+                        upstream_address: upstream_address.clone(),
+                        upstream_path: path.clone(),
+                        client,
+                        path,
+                        method,
+                        route_id: String::from("id1"),
+                        params: Vec::new(),
+                        affinity_cookie: None,
+                        sticky_cookie: None,
+                        mirror: mirrored.then(|| upstream_address.clone()),
+                        timeouts: timeouts.clone(),
+                        retry: retry.clone(),
+                    },
+                    None => Event::NoHealthyUpstream {
+                        cmd_id: id,
+                        route_id: String::from("id1"),
+                    },
+                };
+                send_evt.send(event).unwrap();
+            }
+        }
+    }
+
+    fn address_of(upstream: &SocketAddr) -> UpstreamAddress {
+        let octets = match upstream.ip() {
+            std::net::IpAddr::V4(ip) => ip.octets(),
+            std::net::IpAddr::V6(_) => panic!("IPv4 upstream expected"),
+        };
+        UpstreamAddress::IPv4((octets[0], octets[1], octets[2], octets[3], upstream.port()))
+    }
+
+    /// Starts an upstream that reports the URI of every request it receives, and counts the
+    /// connections it accepts
+    async fn stub_upstream() -> (
+        SocketAddr,
+        mpsc::UnboundedReceiver<String>,
+        Arc<AtomicUsize>,
+    ) {
+        let (send_uri, recv_uri) = mpsc::unbounded_channel();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let make_service = make_service_fn(move |_conn| {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let send_uri = send_uri.clone();
+            let service = service_fn(move |request: Request<Body>| {
+                send_uri.send(request.uri().to_string()).unwrap();
+                async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        (address, recv_uri, connections)
+    }
+
+    /// Starts an upstream that answers every request with the given status
+    async fn status_upstream(status: u16) -> SocketAddr {
+        let make_service = make_service_fn(move |_conn| {
+            let service = service_fn(move |_request: Request<Body>| async move {
+                let response = Response::builder().status(status).body(Body::from("done"));
+                Ok::<_, Infallible>(response.unwrap())
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    /// Starts an upstream that answers every request with the body it received
+    async fn echo_upstream() -> SocketAddr {
+        let make_service = make_service_fn(move |_conn| {
+            let service = service_fn(move |request: Request<Body>| async move {
+                Ok::<_, Infallible>(Response::new(request.into_body()))
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    /// Returns an address nothing can listen on, so connecting to it always fails
+    fn closed_upstream() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    /// Starts an upstream that waits before sending the response head, and again before sending
+    /// the last part of the body
+    async fn slow_upstream(head_delay: Duration, body_delay: Duration) -> SocketAddr {
+        let make_service = make_service_fn(move |_conn| {
+            let service = service_fn(move |_request: Request<Body>| async move {
+                tokio::time::sleep(head_delay).await;
+                let (mut sender, body) = Body::channel();
+                tokio::spawn(async move {
+                    sender.send_data("first, ".into()).await.unwrap();
+                    tokio::time::sleep(body_delay).await;
+                    let _ = sender.send_data("second".into()).await;
+                });
+                Ok::<_, Infallible>(Response::new(body))
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
+
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+}
@@ -1,8 +1,17 @@
-use crate::modules::core::upstream::{Upstream, UpstreamStrategy};
+use crate::modules::core::route::{
+    PredicateCondition, PredicateSource, RequestPredicate, RetryOn, RetryPolicy, RewriteRule,
+    TrafficSplit, UpstreamTimeouts,
+};
+use crate::modules::core::upstream::{HashKey, Upstream, UpstreamStrategy};
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
 use regex::Regex;
 use serde::Deserialize;
 use serde::Serialize;
 use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_LATENCY_DECAY: f64 = 0.8;
 
 const IPV4_REGEX: &str = "^(\\d|[1-9]\\d|1\\d\\d|2[0-4]\\d|25[0-5])\\.(\\d|[1-9]\\d|1\\d\\d|2[0-4]\\d|25[0-5])\\.(\\d|[1-9]\\d|1\\d\\d|2[0-4]\\d|25[0-5])\\.(\\d|[1-9]\\d|1\\d\\d|2[0-4]\\d|25[0-5])(:(0|[1-9][0-9]{0,3}|[1-5][0-9]{4}|6[0-4][0-9]{3}|65[0-4][0-9]{2}|655[0-2][0-9]|6553[0-5]))*$";
 
@@ -13,17 +22,49 @@ pub(crate) struct Route {
     pub methods: Vec<String>,
     pub paths: Vec<String>,
     pub strategy: Strategy,
-    pub upstreams: Vec<String>,
+    #[serde(default)]
+    pub upstreams: Vec<UpstreamEntry>, // empty for the Failover strategy, see `tiers`
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub predicates: Vec<Predicate>,
+    #[serde(default)]
+    pub hash_key: Option<HashOn>, // only used by the ConsistentHash strategy, client by default
+    #[serde(default)]
+    pub sticky_cookie: Option<String>,
+    #[serde(default)]
+    pub latency_decay: Option<f64>, // only used by the LeastLatency strategy
+    #[serde(default)]
+    pub tiers: Vec<Tier>, // only used by the Failover strategy, in order of preference
+    #[serde(default)]
+    pub split: Option<Split>,
+    #[serde(default)]
+    pub mirror: Option<String>, // upstream getting a copy of every request, responses discarded
+    #[serde(default)]
+    pub rewrites: Vec<Rewrite>, // applied in order to the path forwarded to the upstream
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    #[serde(default)]
+    pub retry: Option<Retry>,
 }
 
 impl From<crate::modules::core::route::Route> for Route {
     fn from(route: crate::modules::core::route::Route) -> Self {
-        let upstreams: Vec<String> = route
-            .strategy
-            .get_upstreams()
-            .iter()
-            .map(|u| u.address.to_string())
-            .collect();
+        let (upstreams, tiers) = match &route.strategy {
+            UpstreamStrategy::Failover { tiers } => (
+                Vec::new(),
+                tiers
+                    .iter()
+                    .map(|tier| Tier {
+                        strategy: Strategy::from(tier.clone()),
+                        upstreams: entries_for(tier),
+                    })
+                    .collect(),
+            ),
+            strategy => (entries_for(strategy), Vec::new()),
+        };
 
         Route {
             id: route.id.clone(),
@@ -31,45 +72,434 @@ impl From<crate::modules::core::route::Route> for Route {
             methods: route.methods.clone(),
             paths: route.paths.clone(),
             upstreams,
+            hash_key: route.strategy.hash_key().cloned().map(HashOn::from),
+            latency_decay: latency_decay_of(&route.strategy),
             strategy: Strategy::from(route.strategy),
+            priority: route.priority,
+            hosts: route.hosts.clone(),
+            predicates: route.predicates.iter().map(|p| Predicate::from(p.clone())).collect(),
+            sticky_cookie: route.sticky_cookie.clone(),
+            tiers,
+            split: route.split.clone().map(Split::from),
+            mirror: route.mirror.as_ref().map(|address| address.to_string()),
+            rewrites: route.rewrites.iter().map(|r| Rewrite::from(r.clone())).collect(),
+            timeouts: Timeouts::from(route.timeouts.clone()),
+            retry: route.retry.clone().map(Retry::from),
         }
     }
 }
 
 impl From<Route> for crate::modules::core::route::Route {
     fn from(serializable_route: Route) -> Self {
-        let mut upstreams = Vec::new();
         let regex = Regex::new(IPV4_REGEX).unwrap();
+        let upstreams = upstreams_for(&regex, &serializable_route.upstreams);
+        let strategy = strategy_for(
+            &serializable_route,
+            &serializable_route.strategy,
+            upstreams,
+            &serializable_route.tiers,
+        );
+
+        let mut route = crate::modules::core::route::Route::build(
+            serializable_route.id.clone(),
+            serializable_route.name.clone(),
+            serializable_route.methods.clone(),
+            serializable_route.paths.clone(),
+            strategy,
+        );
+        route.priority = serializable_route.priority;
+        route.sticky_cookie = serializable_route.sticky_cookie.clone();
+        route.split = serializable_route.split.as_ref().map(|split| {
+            let upstreams = upstreams_for(&regex, &split.upstreams);
+            let canary = strategy_for(&serializable_route, &split.strategy, upstreams, &[]);
+            TrafficSplit::build(canary, split.percentage, split.sticky)
+        });
+        route.mirror = serializable_route.mirror.as_ref().and_then(|address| {
+            let entries = [UpstreamEntry::Address(address.clone())];
+            upstreams_for(&regex, &entries).pop().map(|upstream| upstream.address)
+        });
+        route.rewrites = serializable_route
+            .rewrites
+            .iter()
+            .map(|r| RewriteRule::from(r.clone()))
+            .collect();
+        route.timeouts = UpstreamTimeouts::from(serializable_route.timeouts.clone());
+        route.retry = serializable_route.retry.clone().map(RetryPolicy::from);
+        route.hosts = serializable_route.hosts.clone();
+        route.predicates = serializable_route
+            .predicates
+            .iter()
+            .map(|p| RequestPredicate::from(p.clone()))
+            .collect();
+        route
+    }
+}
 
-        for u in serializable_route.upstreams {
-            let upstream = if regex.is_match(u.as_str()) {
-                let tuple = upstream_str_to_tuple(&regex, u.as_str());
-                Upstream::build_from_ipv4(tuple)
-            } else {
-                Upstream::build_from_fqdn(u.as_str())
-            };
-            upstreams.push(upstream)
-        }
-
-        match serializable_route.strategy {
-            Strategy::AlwaysFirst => crate::modules::core::route::Route::build(
-                serializable_route.id.clone(),
-                serializable_route.name.clone(),
-                serializable_route.methods.clone(),
-                serializable_route.paths.clone(),
-                UpstreamStrategy::AlwaysFirst { upstreams },
-            ),
-            Strategy::RoundRobin => crate::modules::core::route::Route::build(
-                serializable_route.id.clone(),
-                serializable_route.name.clone(),
-                serializable_route.methods.clone(),
-                serializable_route.paths.clone(),
-                UpstreamStrategy::RoundRobin { upstreams, next_index: 0 },
-            ),
+/// Builds the strategy of the given route, or of one of its tiers (which have no tiers of their
+/// own), out of the given upstreams
+fn strategy_for(
+    route: &Route,
+    strategy: &Strategy,
+    upstreams: Vec<Upstream>,
+    tiers: &[Tier],
+) -> UpstreamStrategy {
+    match strategy {
+        Strategy::AlwaysFirst => UpstreamStrategy::AlwaysFirst { upstreams },
+        Strategy::RoundRobin => UpstreamStrategy::RoundRobin { upstreams, next_index: 0 },
+        Strategy::WeightedRoundRobin => {
+            UpstreamStrategy::WeightedRoundRobin { upstreams, current_weights: Vec::new() }
+        }
+        Strategy::LeastConnections => {
+            UpstreamStrategy::LeastConnections { upstreams, next_index: 0 }
+        }
+        Strategy::ConsistentHash => UpstreamStrategy::ConsistentHash {
+            upstreams,
+            key: route.hash_key.clone().map_or(HashKey::Client, HashKey::from),
+            ring: Vec::new(),
+            next_index: 0,
+        },
+        Strategy::Random => UpstreamStrategy::Random { upstreams, rng: SmallRng::from_entropy() },
+        Strategy::PowerOfTwoChoices => {
+            UpstreamStrategy::PowerOfTwoChoices { upstreams, rng: SmallRng::from_entropy() }
+        }
+        Strategy::LeastLatency => UpstreamStrategy::LeastLatency {
+            upstreams,
+            decay: route.latency_decay.unwrap_or(DEFAULT_LATENCY_DECAY).clamp(0.0, 1.0),
+            latencies: Vec::new(),
+        },
+        Strategy::Failover => {
+            let regex = Regex::new(IPV4_REGEX).unwrap();
+            let tiers = tiers
+                .iter()
+                .map(|tier| {
+                    let upstreams = upstreams_for(&regex, &tier.upstreams);
+                    strategy_for(route, &tier.strategy, upstreams, &[])
+                })
+                .collect();
+            UpstreamStrategy::Failover { tiers }
+        }
+    }
+}
+
+fn upstreams_for(regex: &Regex, entries: &[UpstreamEntry]) -> Vec<Upstream> {
+    let mut upstreams = Vec::new();
+
+    for u in entries {
+        let (address, weight) = match u {
+            UpstreamEntry::Address(address) => (address, 1),
+            UpstreamEntry::Weighted { address, weight } => (address, *weight),
+        };
+        let mut upstream = if regex.is_match(address.as_str()) {
+            let tuple = upstream_str_to_tuple(regex, address.as_str());
+            Upstream::build_from_ipv4(tuple)
+        } else {
+            Upstream::build_from_fqdn(address.as_str())
+        };
+        upstream.weight = weight;
+        upstreams.push(upstream)
+    }
+    upstreams
+}
+
+fn entries_for(strategy: &UpstreamStrategy) -> Vec<UpstreamEntry> {
+    strategy
+        .get_upstreams()
+        .iter()
+        .map(|u| UpstreamEntry::from((*u).clone()))
+        .collect()
+}
+
+fn latency_decay_of(strategy: &UpstreamStrategy) -> Option<f64> {
+    match strategy {
+        UpstreamStrategy::LeastLatency { decay, .. } => Some(*decay),
+        UpstreamStrategy::Failover { tiers } => tiers.iter().find_map(latency_decay_of),
+        _ => None,
+    }
+}
+
+/// Canary upstreams of a route, getting `percentage` of its requests. Sticky splits keep each
+/// client on the same side
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct Split {
+    pub strategy: Strategy,
+    pub upstreams: Vec<UpstreamEntry>,
+    pub percentage: u8,
+    #[serde(default)]
+    pub sticky: bool,
+}
+
+impl From<TrafficSplit> for Split {
+    fn from(split: TrafficSplit) -> Self {
+        Split {
+            upstreams: entries_for(&split.canary),
+            strategy: Strategy::from(split.canary),
+            percentage: split.percentage,
+            sticky: split.sticky,
+        }
+    }
+}
+
+/// Body of a traffic split change, such as `{"percentage": 5}`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct SplitChange {
+    pub percentage: u8,
+}
+
+/// Group of upstreams of a `Failover` route, balanced by its own strategy
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct Tier {
+    pub strategy: Strategy,
+    pub upstreams: Vec<UpstreamEntry>,
+}
+
+/// Upstream address, either plain (`"localhost:8001"`) or weighted
+/// (`{"address": "localhost:8001", "weight": 9}`). Plain addresses have weight 1
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub(crate) enum UpstreamEntry {
+    Address(String),
+    Weighted { address: String, weight: u32 },
+}
+
+impl From<Upstream> for UpstreamEntry {
+    fn from(upstream: Upstream) -> Self {
+        if upstream.weight == 1 {
+            UpstreamEntry::Address(upstream.address.to_string())
+        } else {
+            UpstreamEntry::Weighted {
+                address: upstream.address.to_string(),
+                weight: upstream.weight,
+            }
+        }
+    }
+}
+
+/// What consistent hashing is keyed on: `"client"`, `{"header": "X-User-Id"}` or
+/// `{"cookie": "session"}`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HashOn {
+    Client,
+    Header(String),
+    Cookie(String),
+}
+
+impl From<HashKey> for HashOn {
+    fn from(key: HashKey) -> Self {
+        match key {
+            HashKey::Client => HashOn::Client,
+            HashKey::Header(name) => HashOn::Header(name),
+            HashKey::Cookie(name) => HashOn::Cookie(name),
+        }
+    }
+}
+
+impl From<HashOn> for HashKey {
+    fn from(hash_on: HashOn) -> Self {
+        match hash_on {
+            HashOn::Client => HashKey::Client,
+            HashOn::Header(name) => HashKey::Header(name),
+            HashOn::Cookie(name) => HashKey::Cookie(name),
+        }
+    }
+}
+
+/// Upstream deadlines of a route in milliseconds, such as `{"connect_ms": 500, "total_ms": 5000}`.
+/// Missing ones fall back to the defaults in the settings
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+#[serde(default)]
+pub(crate) struct Timeouts {
+    pub connect_ms: Option<u64>,
+    pub first_byte_ms: Option<u64>,
+    pub total_ms: Option<u64>,
+}
+
+impl From<UpstreamTimeouts> for Timeouts {
+    fn from(timeouts: UpstreamTimeouts) -> Self {
+        let millis_of = |timeout: Option<Duration>| timeout.map(|t| t.as_millis() as u64);
+        Timeouts {
+            connect_ms: millis_of(timeouts.connect),
+            first_byte_ms: millis_of(timeouts.first_byte),
+            total_ms: millis_of(timeouts.total),
+        }
+    }
+}
+
+impl From<Timeouts> for UpstreamTimeouts {
+    fn from(timeouts: Timeouts) -> Self {
+        UpstreamTimeouts {
+            connect: timeouts.connect_ms.map(Duration::from_millis),
+            first_byte: timeouts.first_byte_ms.map(Duration::from_millis),
+            total: timeouts.total_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Retry policy of a route, such as `{"max_attempts": 3, "retry_on": ["connect_error", "503"]}`.
+/// Missing fields fall back to 2 attempts of idempotent requests on any of the retryable
+/// failures, waiting 25ms before the first retry
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(default)]
+pub(crate) struct Retry {
+    pub max_attempts: u32,
+    pub retry_on: Vec<RetryCondition>,
+    pub idempotent_only: bool,
+    pub backoff_ms: u64,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry::from(RetryPolicy::build(2))
+    }
+}
+
+impl From<RetryPolicy> for Retry {
+    fn from(policy: RetryPolicy) -> Self {
+        Retry {
+            max_attempts: policy.max_attempts,
+            retry_on: policy.retry_on.into_iter().map(RetryCondition::from).collect(),
+            idempotent_only: policy.idempotent_only,
+            backoff_ms: policy.backoff.as_millis() as u64,
         }
     }
 }
 
+impl From<Retry> for RetryPolicy {
+    fn from(retry: Retry) -> Self {
+        RetryPolicy {
+            max_attempts: retry.max_attempts,
+            retry_on: retry.retry_on.into_iter().map(RetryOn::from).collect(),
+            idempotent_only: retry.idempotent_only,
+            backoff: Duration::from_millis(retry.backoff_ms),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) enum RetryCondition {
+    #[serde(rename = "connect_error")]
+    ConnectError,
+    #[serde(rename = "502")]
+    BadGateway,
+    #[serde(rename = "503")]
+    ServiceUnavailable,
+    #[serde(rename = "504")]
+    GatewayTimeout,
+}
+
+impl From<RetryOn> for RetryCondition {
+    fn from(retry_on: RetryOn) -> Self {
+        match retry_on {
+            RetryOn::ConnectError => RetryCondition::ConnectError,
+            RetryOn::BadGateway => RetryCondition::BadGateway,
+            RetryOn::ServiceUnavailable => RetryCondition::ServiceUnavailable,
+            RetryOn::GatewayTimeout => RetryCondition::GatewayTimeout,
+        }
+    }
+}
+
+impl From<RetryCondition> for RetryOn {
+    fn from(condition: RetryCondition) -> Self {
+        match condition {
+            RetryCondition::ConnectError => RetryOn::ConnectError,
+            RetryCondition::BadGateway => RetryOn::BadGateway,
+            RetryCondition::ServiceUnavailable => RetryOn::ServiceUnavailable,
+            RetryCondition::GatewayTimeout => RetryOn::GatewayTimeout,
+        }
+    }
+}
+
+/// Path rewrite rule, such as `{"strip_prefix": "/svc-a"}`, `{"add_prefix": "/v2"}` or
+/// `{"replace": {"pattern": "^/users/(\\d+)$", "replacement": "/accounts/$1"}}`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Rewrite {
+    StripPrefix(String),
+    AddPrefix(String),
+    Replace { pattern: String, replacement: String },
+}
+
+impl From<RewriteRule> for Rewrite {
+    fn from(rule: RewriteRule) -> Self {
+        match rule {
+            RewriteRule::StripPrefix(prefix) => Rewrite::StripPrefix(prefix),
+            RewriteRule::AddPrefix(prefix) => Rewrite::AddPrefix(prefix),
+            RewriteRule::Replace { pattern, replacement } => {
+                Rewrite::Replace { pattern, replacement }
+            }
+        }
+    }
+}
+
+impl From<Rewrite> for RewriteRule {
+    fn from(rewrite: Rewrite) -> Self {
+        match rewrite {
+            Rewrite::StripPrefix(prefix) => RewriteRule::StripPrefix(prefix),
+            Rewrite::AddPrefix(prefix) => RewriteRule::AddPrefix(prefix),
+            Rewrite::Replace { pattern, replacement } => {
+                RewriteRule::Replace { pattern, replacement }
+            }
+        }
+    }
+}
+
+/// Header or query parameter predicate, such as `{"header": "X-Api-Version", "equals": "2"}`,
+/// `{"query": "beta", "matches": "true|yes"}` or `{"header": "X-Debug", "present": false}`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub(crate) struct Predicate {
+    #[serde(flatten)]
+    pub source: Source,
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Source {
+    Header(String),
+    Query(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Condition {
+    Equals(String),
+    Matches(String),
+    Present(bool),
+}
+
+impl From<RequestPredicate> for Predicate {
+    fn from(predicate: RequestPredicate) -> Self {
+        let source = match predicate.source {
+            PredicateSource::Header(name) => Source::Header(name),
+            PredicateSource::Query(name) => Source::Query(name),
+        };
+        let condition = match predicate.condition {
+            PredicateCondition::Equals(value) => Condition::Equals(value),
+            PredicateCondition::Matches(pattern) => Condition::Matches(pattern),
+            PredicateCondition::Present => Condition::Present(true),
+            PredicateCondition::Absent => Condition::Present(false),
+        };
+        Predicate { source, condition }
+    }
+}
+
+impl From<Predicate> for RequestPredicate {
+    fn from(predicate: Predicate) -> Self {
+        let source = match predicate.source {
+            Source::Header(name) => PredicateSource::Header(name),
+            Source::Query(name) => PredicateSource::Query(name),
+        };
+        let condition = match predicate.condition {
+            Condition::Equals(value) => PredicateCondition::Equals(value),
+            Condition::Matches(pattern) => PredicateCondition::Matches(pattern),
+            Condition::Present(true) => PredicateCondition::Present,
+            Condition::Present(false) => PredicateCondition::Absent,
+        };
+        RequestPredicate { source, condition }
+    }
+}
+
 fn upstream_str_to_tuple(regex: &Regex, upstream: &str) -> (u8, u8, u8, u8, u16) {
     let parts = regex.captures(upstream).unwrap();
 
@@ -97,6 +527,13 @@ fn upstream_str_to_tuple(regex: &Regex, upstream: &str) -> (u8, u8, u8, u8, u16)
 pub(crate) enum Strategy {
     AlwaysFirst,
     RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    ConsistentHash,
+    Random,
+    PowerOfTwoChoices,
+    LeastLatency,
+    Failover,
 }
 
 impl From<UpstreamStrategy> for Strategy {
@@ -104,6 +541,13 @@ impl From<UpstreamStrategy> for Strategy {
         match upstream_strategy {
             UpstreamStrategy::AlwaysFirst { .. } => Strategy::AlwaysFirst,
             UpstreamStrategy::RoundRobin { .. } => Strategy::RoundRobin,
+            UpstreamStrategy::WeightedRoundRobin { .. } => Strategy::WeightedRoundRobin,
+            UpstreamStrategy::LeastConnections { .. } => Strategy::LeastConnections,
+            UpstreamStrategy::ConsistentHash { .. } => Strategy::ConsistentHash,
+            UpstreamStrategy::Random { .. } => Strategy::Random,
+            UpstreamStrategy::PowerOfTwoChoices { .. } => Strategy::PowerOfTwoChoices,
+            UpstreamStrategy::LeastLatency { .. } => Strategy::LeastLatency,
+            UpstreamStrategy::Failover { .. } => Strategy::Failover,
         }
     }
 }
@@ -111,10 +555,14 @@ impl From<UpstreamStrategy> for Strategy {
 #[cfg(test)]
 mod tests {
     use crate::infrastructure::serializable_model::{
-        upstream_str_to_tuple, Route, Strategy, IPV4_REGEX,
+        upstream_str_to_tuple, Predicate, Route, Strategy, Timeouts, UpstreamEntry, IPV4_REGEX,
+    };
+    use crate::modules::core::route::{
+        PredicateCondition, PredicateSource, RequestPredicate, RetryOn, RewriteRule,
     };
-    use crate::modules::core::upstream::Upstream;
+    use crate::modules::core::upstream::{HashKey, Upstream, UpstreamAddress, UpstreamStrategy};
     use regex::Regex;
+    use std::time::Duration;
     use crate::modules::core::upstream::UpstreamStrategy::AlwaysFirst;
 
     #[test]
@@ -153,6 +601,267 @@ mod tests {
         assert_eq!(route, sample_route_ipv4())
     }
 
+    #[test]
+    fn should_deserialize_route_predicates() {
+        // given:
+        let json = r#"[
+            {"header": "X-Api-Version", "equals": "2"},
+            {"query": "beta", "matches": "true|yes"},
+            {"header": "X-Debug", "present": false}
+        ]"#;
+
+        // when:
+        let predicates: Vec<Predicate> = serde_json::from_str(json).unwrap();
+        let converted: Vec<RequestPredicate> =
+            predicates.iter().map(|p| RequestPredicate::from(p.clone())).collect();
+
+        // then:
+        assert_eq!(
+            vec![
+                RequestPredicate {
+                    source: PredicateSource::Header(String::from("X-Api-Version")),
+                    condition: PredicateCondition::Equals(String::from("2")),
+                },
+                RequestPredicate {
+                    source: PredicateSource::Query(String::from("beta")),
+                    condition: PredicateCondition::Matches(String::from("true|yes")),
+                },
+                RequestPredicate {
+                    source: PredicateSource::Header(String::from("X-Debug")),
+                    condition: PredicateCondition::Absent,
+                },
+            ],
+            converted
+        );
+        assert_eq!(
+            r#"{"header":"X-Debug","present":false}"#,
+            serde_json::to_string(&predicates[2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_deserialize_weighted_upstreams() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "WeightedRoundRobin",
+            "upstreams": ["upstream1", {"address": "upstream2", "weight": 9}]
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        let weights: Vec<u32> = route.strategy.get_upstreams().iter().map(|u| u.weight).collect();
+        assert_eq!(vec![1, 9], weights);
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_consistent_hash_key() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "ConsistentHash",
+            "upstreams": ["upstream1", "upstream2"],
+            "hash_key": {"cookie": "session"}
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        match &route.strategy {
+            UpstreamStrategy::ConsistentHash { key, .. } => {
+                assert_eq!(&HashKey::Cookie(String::from("session")), key)
+            }
+            other => panic!("unexpected strategy {:?}", other),
+        }
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_failover_tiers() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "Failover",
+            "tiers": [
+                {"strategy": "RoundRobin", "upstreams": ["primary1", "primary2"]},
+                {"strategy": "AlwaysFirst", "upstreams": ["backup1"]}
+            ]
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        assert_eq!(
+            UpstreamStrategy::Failover {
+                tiers: vec![
+                    UpstreamStrategy::RoundRobin {
+                        upstreams: vec![
+                            Upstream::build_from_fqdn("primary1"),
+                            Upstream::build_from_fqdn("primary2"),
+                        ],
+                        next_index: 0,
+                    },
+                    AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("backup1")] },
+                ],
+            },
+            route.strategy
+        );
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_traffic_split() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "RoundRobin",
+            "upstreams": ["stable1", "stable2"],
+            "split": {"strategy": "AlwaysFirst", "upstreams": ["canary"], "percentage": 5}
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        let split = route.split.clone().unwrap();
+        assert_eq!(5, split.percentage);
+        assert!(!split.sticky);
+        assert_eq!(
+            AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("canary")] },
+            split.canary
+        );
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_mirror() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "AlwaysFirst",
+            "upstreams": ["upstream1"],
+            "mirror": "192.168.0.100:8080"
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        assert_eq!(Some(UpstreamAddress::IPv4((192, 168, 0, 100, 8080))), route.mirror);
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_rewrites() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["/svc-a/*rest"],
+            "strategy": "AlwaysFirst",
+            "upstreams": ["upstream1"],
+            "rewrites": [
+                {"strip_prefix": "/svc-a"},
+                {"replace": {"pattern": "^/users/(\\d+)$", "replacement": "/accounts/$1"}},
+                {"add_prefix": "/v2"}
+            ]
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        assert_eq!(
+            vec![
+                RewriteRule::StripPrefix(String::from("/svc-a")),
+                RewriteRule::Replace {
+                    pattern: String::from("^/users/(\\d+)$"),
+                    replacement: String::from("/accounts/$1"),
+                },
+                RewriteRule::AddPrefix(String::from("/v2")),
+            ],
+            route.rewrites
+        );
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_timeouts() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "AlwaysFirst",
+            "upstreams": ["upstream1"],
+            "timeouts": {"connect_ms": 500, "total_ms": 5000}
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        assert_eq!(Some(Duration::from_millis(500)), route.timeouts.connect);
+        assert_eq!(None, route.timeouts.first_byte);
+        assert_eq!(Some(Duration::from_secs(5)), route.timeouts.total);
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
+    #[test]
+    fn should_deserialize_retry() {
+        // given:
+        let json = r#"{
+            "id": "id1",
+            "name": "route1",
+            "methods": ["GET"],
+            "paths": ["uri1"],
+            "strategy": "RoundRobin",
+            "upstreams": ["upstream1", "upstream2"],
+            "retry": {"max_attempts": 3, "retry_on": ["connect_error", "503"]}
+        }"#;
+
+        // when:
+        let serializable_route: Route = serde_json::from_str(json).unwrap();
+        let route: crate::modules::core::route::Route = serializable_route.clone().into();
+
+        // then:
+        let retry = route.retry.clone().unwrap();
+        assert_eq!(3, retry.max_attempts);
+        assert_eq!(vec![RetryOn::ConnectError, RetryOn::ServiceUnavailable], retry.retry_on);
+        assert!(retry.idempotent_only);
+        assert_eq!(Duration::from_millis(25), retry.backoff);
+        assert_eq!(serializable_route, Route::from(route));
+    }
+
     #[test]
     fn should_convert_upstream_str_to_tuple_ip_only() {
         // given:
@@ -215,8 +924,23 @@ mod tests {
             name: String::from("route1"),
             methods: vec![String::from("GET")],
             paths: vec![String::from("uri1"), String::from("uri2")],
-            upstreams: vec![String::from("upstream1"), String::from("upstream2")],
+            upstreams: vec![
+                UpstreamEntry::Address(String::from("upstream1")),
+                UpstreamEntry::Address(String::from("upstream2")),
+            ],
             strategy: Strategy::AlwaysFirst,
+            priority: 0,
+            hosts: Vec::new(),
+            predicates: Vec::new(),
+            hash_key: None,
+            sticky_cookie: None,
+            latency_decay: None,
+            tiers: Vec::new(),
+            split: None,
+            mirror: None,
+            rewrites: Vec::new(),
+            timeouts: Timeouts::default(),
+            retry: None,
         }
     }
 
@@ -227,10 +951,22 @@ mod tests {
             methods: vec![String::from("GET")],
             paths: vec![String::from("uri1"), String::from("uri2")],
             upstreams: vec![
-                String::from("192.168.0.100"),
-                String::from("192.168.0.101:8080"),
+                UpstreamEntry::Address(String::from("192.168.0.100")),
+                UpstreamEntry::Address(String::from("192.168.0.101:8080")),
             ],
             strategy: Strategy::AlwaysFirst,
+            priority: 0,
+            hosts: Vec::new(),
+            predicates: Vec::new(),
+            hash_key: None,
+            sticky_cookie: None,
+            latency_decay: None,
+            tiers: Vec::new(),
+            split: None,
+            mirror: None,
+            rewrites: Vec::new(),
+            timeouts: Timeouts::default(),
+            retry: None,
         }
     }
 }
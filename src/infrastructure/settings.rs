@@ -1,11 +1,13 @@
 use std::fs::File;
 use std::io::BufReader;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::time::Duration;
 
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::infrastructure::upstream_client::UpstreamClientSettings;
 use crate::HapiError;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -15,6 +17,20 @@ pub(crate) struct HapiSettings {
     port: u16,
     api_ip_address: String,
     api_port: u16,
+    #[serde(default)]
+    pub forwarding: ForwardingSettings,
+    #[serde(default)]
+    pub upstream_client: UpstreamClientSettings,
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+    #[serde(default)]
+    pub body_buffer: BodyBufferSettings,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+    #[serde(default)]
+    pub outlier_detection: OutlierDetectionSettings,
+    #[serde(default)]
+    pub error_responses: ErrorResponseSettings,
 }
 
 impl HapiSettings {
@@ -57,6 +73,221 @@ impl ProbeSettings {
     }
 }
 
+/// Controls the proxy headers (`X-Forwarded-*`, `Forwarded` and `Via`) sent to upstreams
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct ForwardingSettings {
+    pub mode: ForwardingMode,
+    pub trusted_proxies: Vec<IpAddr>, // peers whose forwarded headers are kept, in append mode
+    pub via_pseudonym: String, // how Hapi names itself in the `Via` header
+}
+
+impl Default for ForwardingSettings {
+    fn default() -> Self {
+        ForwardingSettings {
+            mode: ForwardingMode::Append,
+            trusted_proxies: Vec::new(),
+            via_pseudonym: String::from("hapi"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum ForwardingMode {
+    Append,    // extend the forwarded headers of trusted proxies, replace anyone else's
+    Overwrite, // always replace the forwarded headers, whoever the peer is
+}
+
+impl ForwardingSettings {
+    /// Forwarded headers are only kept when they come from a trusted proxy, as anyone else could
+    /// forge them
+    pub fn trusts(&self, peer: &IpAddr) -> bool {
+        self.mode == ForwardingMode::Append && self.trusted_proxies.contains(peer)
+    }
+}
+
+/// Default deadlines of the requests forwarded to upstreams, for routes that don't set their own
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct TimeoutSettings {
+    pub connect_ms: u64,
+    pub first_byte_ms: u64,
+    pub total_ms: u64,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        TimeoutSettings {
+            connect_ms: 5000,
+            first_byte_ms: 30000,
+            total_ms: 60000,
+        }
+    }
+}
+
+impl TimeoutSettings {
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect_ms)
+    }
+
+    pub fn first_byte(&self) -> Duration {
+        Duration::from_millis(self.first_byte_ms)
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_millis(self.total_ms)
+    }
+}
+
+/// Request bodies that may be sent more than once are buffered, as long as their length is known
+/// and within this limit. Any other body is streamed to the upstream once
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct BodyBufferSettings {
+    pub max_bytes: u64,
+}
+
+impl Default for BodyBufferSettings {
+    fn default() -> Self {
+        BodyBufferSettings {
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// Circuit breaker of every upstream, driven by the outcome of the requests forwarded to it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct CircuitBreakerSettings {
+    pub enabled: bool,
+    pub consecutive_failures: u64, // opens the circuit after this many failures in a row
+    pub error_rate: f64, // or once this share of the last `window` requests failed...
+    pub min_requests: usize, // ... as long as there were at least this many of them
+    pub window: usize,
+    pub cool_down_ms: u64, // time the circuit stays open before letting trial requests through
+    pub trial_requests: usize, // successful trial requests needed to close the circuit
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        CircuitBreakerSettings {
+            enabled: true,
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window: 100,
+            cool_down_ms: 30000,
+            trial_requests: 3,
+        }
+    }
+}
+
+impl CircuitBreakerSettings {
+    pub fn cool_down(&self) -> Duration {
+        Duration::from_millis(self.cool_down_ms)
+    }
+}
+
+/// Passive outlier detection, comparing the upstreams of each route with each other
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct OutlierDetectionSettings {
+    pub enabled: bool,
+    pub interval_ms: u64, // time between detections
+    pub min_requests: u64, // upstreams with fewer requests since the last detection aren't judged
+    pub min_failure_rate: f64, // upstreams failing less often than this are never ejected...
+    pub failure_factor: f64, // ... otherwise, they are if they fail this many times more than peers
+    pub latency_factor: f64, // or if they are this many times slower than peers
+    pub base_ejection_ms: u64, // multiplied by the number of times the upstream was ejected
+    pub max_ejection_ms: u64,
+    pub max_ejection_percent: u8, // of the upstreams of a route ejected at once, at least one
+}
+
+impl Default for OutlierDetectionSettings {
+    fn default() -> Self {
+        OutlierDetectionSettings {
+            enabled: true,
+            interval_ms: 10000,
+            min_requests: 10,
+            min_failure_rate: 0.2,
+            failure_factor: 3.0,
+            latency_factor: 3.0,
+            base_ejection_ms: 30000,
+            max_ejection_ms: 300000,
+            max_ejection_percent: 10,
+        }
+    }
+}
+
+impl OutlierDetectionSettings {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn base_ejection(&self) -> Duration {
+        Duration::from_millis(self.base_ejection_ms)
+    }
+
+    pub fn max_ejection(&self) -> Duration {
+        Duration::from_millis(self.max_ejection_ms)
+    }
+}
+
+/// Bodies of the responses Hapi sends when it can't get one from an upstream. Templates may use
+/// the `{status}`, `{reason}`, `{message}` and `{request_id}` placeholders
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct ErrorResponseSettings {
+    pub format: ErrorFormat,
+    pub json_template: String,
+    pub html_template: String,
+}
+
+impl Default for ErrorResponseSettings {
+    fn default() -> Self {
+        ErrorResponseSettings {
+            format: ErrorFormat::Json,
+            json_template: String::from(concat!(
+                r#"{"status":{status},"error":"{reason}","#,
+                r#""message":"{message}","request_id":"{request_id}"}"#,
+            )),
+            html_template: String::from(
+                "<!DOCTYPE html><html><head><title>{status} {reason}</title></head><body>\
+                <h1>{status} {reason}</h1><p>{message}</p><p>Request ID: {request_id}</p>\
+                </body></html>",
+            ),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) enum ErrorFormat {
+    Json,
+    Html,
+}
+
+impl ErrorResponseSettings {
+    /// Returns the content type and body of an error response
+    pub fn render(
+        &self,
+        status: u16,
+        reason: &str,
+        message: &str,
+        request_id: &str,
+    ) -> (&'static str, String) {
+        let (content_type, template) = match self.format {
+            ErrorFormat::Json => ("application/json", &self.json_template),
+            ErrorFormat::Html => ("text/html; charset=utf-8", &self.html_template),
+        };
+        let body = template
+            .replace("{status}", status.to_string().as_str())
+            .replace("{reason}", reason)
+            .replace("{message}", message)
+            .replace("{request_id}", request_id);
+        (content_type, body)
+    }
+}
+
 fn socket_address(ip: &str, port: u16) -> String {
     let mut result = String::from(ip);
     result.push_str(":");
@@ -1,9 +1,15 @@
 use crate::errors::HapiError;
 use crate::events::commands::Command;
-use crate::events::commands::Command::LookupStats;
+use crate::events::commands::Command::{
+    CountOutcome, LookupEjections, LookupFailovers, LookupOutcomes, LookupStats,
+};
 use crate::events::events::Event;
-use crate::events::events::Event::{StatsWereFound, UpstreamWasFound};
-use crate::modules::stats::Stats;
+use crate::events::events::Event::{
+    EjectionsWereFound, FailoversWereFound, NoHealthyUpstream, OutcomesWereFound,
+    RouteWasFailedBack, RouteWasFailedOver, RouteWasNotMatched, StatsWereFound, UpstreamWasEjected,
+    UpstreamWasFound, UpstreamWasRestored,
+};
+use crate::modules::stats::{Outcome, Stats};
 use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast::{Receiver, Sender};
 use uuid::Uuid;
@@ -31,6 +37,42 @@ pub(crate) async fn handle_stats(
                     stats: result,
                 })
             }
+            LookupOutcomes { id } => {
+                let sts = stats2.lock().unwrap();
+                Some(OutcomesWereFound {
+                    cmd_id: id,
+                    outcomes: sts.get_outcomes(),
+                })
+            }
+            LookupEjections { id } => {
+                let sts = stats2.lock().unwrap();
+                Some(EjectionsWereFound {
+                    cmd_id: id,
+                    ejections: sts.get_ejections(),
+                })
+            }
+            LookupFailovers { id } => {
+                let sts = stats2.lock().unwrap();
+                Some(FailoversWereFound {
+                    cmd_id: id,
+                    failovers: sts.get_failovers(),
+                })
+            }
+            CountOutcome {
+                id,
+                route_id,
+                upstream_address,
+                outcome,
+            } => {
+                log::debug!("Counting outcome {} of command {}", outcome, id);
+                let mut sts = stats2.lock().unwrap();
+                sts.count_outcome(
+                    route_id.as_str(),
+                    upstream_address.to_string().as_str(),
+                    &outcome,
+                );
+                None
+            }
             _ => None,
         };
 
@@ -82,6 +124,87 @@ impl StatsClient {
             }
         }
     }
+
+    pub async fn get_outcomes(&mut self) -> Result<Vec<(String, String, String, u64)>, HapiError> {
+        let cmd_uuid = Uuid::new_v4();
+        let command = LookupOutcomes {
+            id: cmd_uuid.to_string(),
+        };
+        self.send_cmd.send(command)?;
+
+        loop {
+            match self.recv_evt.recv().await {
+                Ok(event) => {
+                    log::debug!("Received event {:?}", event);
+                    if let OutcomesWereFound { cmd_id, outcomes } = event {
+                        if cmd_id == cmd_uuid.to_string() {
+                            break Ok(outcomes);
+                        }
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Error receiving message {:?}", error);
+                    break Err(HapiError::MessageReceiveError(error));
+                }
+            }
+        }
+    }
+
+    /// Returns the (route id, upstream, times ejected, whether it is ejected now) of every
+    /// upstream ever ejected from a route
+    pub async fn get_ejections(&mut self) -> Result<Vec<(String, String, u64, bool)>, HapiError> {
+        let cmd_uuid = Uuid::new_v4();
+        let command = LookupEjections {
+            id: cmd_uuid.to_string(),
+        };
+        self.send_cmd.send(command)?;
+
+        loop {
+            match self.recv_evt.recv().await {
+                Ok(event) => {
+                    log::debug!("Received event {:?}", event);
+                    if let EjectionsWereFound { cmd_id, ejections } = event {
+                        if cmd_id == cmd_uuid.to_string() {
+                            break Ok(ejections);
+                        }
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Error receiving message {:?}", error);
+                    break Err(HapiError::MessageReceiveError(error));
+                }
+            }
+        }
+    }
+
+    /// Returns the (route id, times failed over, times failed back, active tier) of every route
+    /// that changed tiers
+    pub async fn get_failovers(
+        &mut self,
+    ) -> Result<Vec<(String, u64, u64, Option<usize>)>, HapiError> {
+        let cmd_uuid = Uuid::new_v4();
+        let command = LookupFailovers {
+            id: cmd_uuid.to_string(),
+        };
+        self.send_cmd.send(command)?;
+
+        loop {
+            match self.recv_evt.recv().await {
+                Ok(event) => {
+                    log::debug!("Received event {:?}", event);
+                    if let FailoversWereFound { cmd_id, failovers } = event {
+                        if cmd_id == cmd_uuid.to_string() {
+                            break Ok(failovers);
+                        }
+                    }
+                }
+                Err(error) => {
+                    log::warn!("Error receiving message {:?}", error);
+                    break Err(HapiError::MessageReceiveError(error));
+                }
+            }
+        }
+    }
 }
 
 async fn event_listener(mut recv_evt: Receiver<Event>, stats: Arc<Mutex<Stats>>) {
@@ -102,6 +225,38 @@ async fn event_listener(mut recv_evt: Receiver<Event>, stats: Arc<Mutex<Stats>>)
                     upstream_address.to_string().as_str(),
                 )
             }
+            UpstreamWasEjected {
+                route_id,
+                upstream_address,
+                ..
+            } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_ejection(route_id.as_str(), upstream_address.to_string().as_str())
+            }
+            UpstreamWasRestored {
+                route_id,
+                upstream_address,
+                ..
+            } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_restoration(route_id.as_str(), upstream_address.to_string().as_str())
+            }
+            RouteWasFailedOver { route_id, to_tier } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_failover(route_id.as_str(), to_tier)
+            }
+            RouteWasFailedBack { route_id, to_tier } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_failback(route_id.as_str(), to_tier)
+            }
+            RouteWasNotMatched { .. } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_outcome("", "", &Outcome::RouteNotMatched)
+            }
+            NoHealthyUpstream { route_id, .. } => {
+                let mut sts = stats.lock().unwrap();
+                sts.count_outcome(route_id.as_str(), "", &Outcome::NoHealthyUpstream)
+            }
             _ => {}
         }
     }
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hyper::client::HttpConnector;
+use hyper::Client;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Connection pool of the client shared by every request forwarded to upstreams
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub(crate) struct UpstreamClientSettings {
+    pub keep_alive: bool, // reuse connections to upstreams across requests
+    pub pool_max_idle_per_host: usize, // idle connections kept open per upstream
+    pub pool_idle_timeout_ms: u64, // idle connections are closed after this long
+    pub tcp_keepalive_ms: Option<u64>, // interval of TCP keep-alive probes, none to disable them
+}
+
+impl Default for UpstreamClientSettings {
+    fn default() -> Self {
+        UpstreamClientSettings {
+            keep_alive: true,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90000,
+            tcp_keepalive_ms: Some(60000),
+        }
+    }
+}
+
+pub(crate) type UpstreamClient = Client<HttpConnector>;
+
+/// Clients shared by every forwarded request, so connections to upstreams are pooled and reused
+/// instead of paying a handshake per request. The connect timeout belongs to the connector, so
+/// there is one client per connect timeout in use
+#[derive(Clone)]
+pub(crate) struct UpstreamClients {
+    settings: UpstreamClientSettings,
+    clients: Arc<Mutex<HashMap<Duration, UpstreamClient>>>, // connect timeout => client
+}
+
+impl UpstreamClients {
+    pub fn build(settings: &UpstreamClientSettings) -> Self {
+        UpstreamClients {
+            settings: settings.clone(),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_connect_timeout(&self, connect_timeout: Duration) -> UpstreamClient {
+        let mut clients = self.clients.lock().unwrap();
+        clients
+            .entry(connect_timeout)
+            .or_insert_with(|| build_upstream_client(&self.settings, connect_timeout))
+            .clone()
+    }
+}
+
+fn build_upstream_client(
+    settings: &UpstreamClientSettings,
+    connect_timeout: Duration,
+) -> UpstreamClient {
+    let mut connector = HttpConnector::new();
+    connector.set_connect_timeout(Some(connect_timeout));
+    connector.set_keepalive(settings.tcp_keepalive_ms.map(Duration::from_millis));
+    let max_idle_per_host = if settings.keep_alive {
+        settings.pool_max_idle_per_host
+    } else {
+        0
+    };
+
+    Client::builder()
+        .pool_max_idle_per_host(max_idle_per_host)
+        .pool_idle_timeout(Duration::from_millis(settings.pool_idle_timeout_ms))
+        .build(connector)
+}
@@ -1,8 +1,10 @@
 use crate::errors::HapiError;
 use crate::events::commands::Command;
 use crate::events::events::Event;
+use crate::infrastructure::breaker_handler::BreakerClient;
 use crate::infrastructure::core_handler::CoreClient;
 use crate::infrastructure::stats_handler::StatsClient;
+use crate::modules::core::context::CoreError;
 use crate::modules::core::route::Route;
 use hyper::{header, Body, Method, Request, Response};
 use std::str::FromStr;
@@ -20,6 +22,7 @@ pub(crate) async fn handle_api(
 
     let resource = ApiResource::from_str(path_parts[1]).unwrap();
     let resource_id = path_parts.get(2); // TODO: consider that resource_id could be empty
+    let sub_resource = path_parts.get(3).copied();
     let method = request.method();
 
     let response = match (resource, method, resource_id) {
@@ -51,8 +54,38 @@ pub(crate) async fn handle_api(
             match requested_route {
                 Ok(route) => {
                     log::debug!("Route received {:?}", route);
-                    add_route(route, send_cmd, recv_evt).await;
-                    created()
+                    match add_route(route, send_cmd, recv_evt).await {
+                        Ok(shadowed_by) if shadowed_by.is_empty() => created(),
+                        Ok(shadowed_by) => created_with_warning(format!(
+                            "route is shadowed by {}",
+                            shadowed_by.join(", ")
+                        )),
+                        Err(e) => bad_request(e),
+                    }
+                }
+                Err(e) => bad_request(e),
+            }
+        }
+        (ApiResource::Route, &Method::PUT, Some(r_id)) if sub_resource == Some("split") => {
+            let requested_change: Result<
+                crate::infrastructure::serializable_model::SplitChange,
+                HapiError,
+            > = match hyper::body::to_bytes(request.into_body()).await {
+                Ok(bytes) => serde_json::from_slice(&bytes).map_err(HapiError::SerdeError),
+                Err(e) => Err(HapiError::HyperError(e)),
+            };
+
+            match requested_change {
+                Ok(change) => {
+                    let result = set_traffic_split(r_id, change.percentage, send_cmd, recv_evt);
+                    match result.await {
+                        Ok(route) => {
+                            let content = serde_json::to_string(&route).unwrap(); // TODO: remove unwrap
+                            json(content)
+                        }
+                        Err(HapiError::CoreError(CoreError::RouteNotExists)) => not_found(),
+                        Err(e) => bad_request(e),
+                    }
                 }
                 Err(e) => bad_request(e),
             }
@@ -75,6 +108,15 @@ pub(crate) async fn handle_api(
                 Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
             }
         }
+        (ApiResource::Upstream, &Method::GET, Some(&"circuits")) => {
+            match get_circuits(send_cmd, recv_evt).await {
+                Ok(circuits) => {
+                    let content = serde_json::to_string(&circuits).unwrap(); // TODO: remove unwrap
+                    json(content)
+                }
+                Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
+            }
+        }
         (ApiResource::Stats, &Method::GET, None) => {
             match get_stats(send_cmd, recv_evt).await {
                 Ok(stats) => {
@@ -84,6 +126,33 @@ pub(crate) async fn handle_api(
                 Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
             }
         }
+        (ApiResource::Stats, &Method::GET, Some(&"ejections")) => {
+            match get_ejections(send_cmd, recv_evt).await {
+                Ok(ejections) => {
+                    let content = serde_json::to_string(&ejections).unwrap(); // TODO: remove unwrap
+                    json(content)
+                }
+                Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
+            }
+        }
+        (ApiResource::Stats, &Method::GET, Some(&"failovers")) => {
+            match get_failovers(send_cmd, recv_evt).await {
+                Ok(failovers) => {
+                    let content = serde_json::to_string(&failovers).unwrap(); // TODO: remove unwrap
+                    json(content)
+                }
+                Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
+            }
+        }
+        (ApiResource::Stats, &Method::GET, Some(&"outcomes")) => {
+            match get_outcomes(send_cmd, recv_evt).await {
+                Ok(outcomes) => {
+                    let content = serde_json::to_string(&outcomes).unwrap(); // TODO: remove unwrap
+                    json(content)
+                }
+                Err(e) => bad_request(e), // TODO: maybe this isn't a 4xx?
+            }
+        }
         _ => {
             not_found() // TODO: remove
         }
@@ -144,13 +213,14 @@ async fn add_route(
     route: crate::infrastructure::serializable_model::Route,
     send_cmd: Sender<Command>,
     recv_evt: Receiver<Event>,
-) {
+) -> Result<Vec<String>, HapiError> {
     let mut core_client = CoreClient::build(send_cmd, recv_evt);
     let r = Route::from(route);
-    match core_client.add_route(r).await {
-        Ok(()) => {}
-        Err(e) => log::error!("Error adding route: {:?}", e),
+    let result = core_client.add_route(r).await;
+    if let Err(e) = &result {
+        log::error!("Error adding route: {:?}", e);
     }
+    result
 }
 
 async fn remove_route(
@@ -165,6 +235,19 @@ async fn remove_route(
         .map(|r| crate::infrastructure::serializable_model::Route::from(r))
 }
 
+async fn set_traffic_split(
+    route_id: &str,
+    percentage: u8,
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<crate::infrastructure::serializable_model::Route, HapiError> {
+    let mut core_client = CoreClient::build(send_cmd, recv_evt);
+    core_client
+        .set_traffic_split(route_id, percentage)
+        .await
+        .map(crate::infrastructure::serializable_model::Route::from)
+}
+
 async fn get_upstreams(
     send_cmd: Sender<Command>,
     recv_evt: Receiver<Event>,
@@ -179,6 +262,14 @@ async fn get_upstreams(
     })
 }
 
+async fn get_circuits(
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<Vec<(String, String)>, HapiError> {
+    let mut breaker_client = BreakerClient::build(send_cmd, recv_evt);
+    breaker_client.get_circuits().await
+}
+
 async fn get_stats(
     send_cmd: Sender<Command>,
     recv_evt: Receiver<Event>,
@@ -187,6 +278,30 @@ async fn get_stats(
     stats_client.get_all_stats().await
 }
 
+async fn get_outcomes(
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<Vec<(String, String, String, u64)>, HapiError> {
+    let mut stats_client = StatsClient::build(send_cmd, recv_evt);
+    stats_client.get_outcomes().await
+}
+
+async fn get_ejections(
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<Vec<(String, String, u64, bool)>, HapiError> {
+    let mut stats_client = StatsClient::build(send_cmd, recv_evt);
+    stats_client.get_ejections().await
+}
+
+async fn get_failovers(
+    send_cmd: Sender<Command>,
+    recv_evt: Receiver<Event>,
+) -> Result<Vec<(String, u64, u64, Option<usize>)>, HapiError> {
+    let mut stats_client = StatsClient::build(send_cmd, recv_evt);
+    stats_client.get_failovers().await
+}
+
 fn ok() -> Response<Body> {
     Response::builder().status(200).body(Body::empty()).unwrap()
 }
@@ -195,6 +310,14 @@ fn created() -> Response<Body> {
     Response::builder().status(201).body(Body::empty()).unwrap()
 }
 
+fn created_with_warning(warning: String) -> Response<Body> {
+    Response::builder()
+        .header(header::WARNING, format!("299 hapi \"{}\"", warning))
+        .status(201)
+        .body(Body::empty())
+        .unwrap()
+}
+
 fn not_found() -> Response<Body> {
     Response::builder().status(404).body(Body::empty()).unwrap()
 }
@@ -211,3 +334,79 @@ fn json(json: String) -> Response<Body> {
         .body(Body::from(json))
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Method, Request};
+    use tokio::sync::broadcast;
+    use tokio::sync::broadcast::{Receiver, Sender};
+
+    use crate::events::commands::Command;
+    use crate::events::events::Event;
+    use crate::interfaces::api::handle_api;
+    use crate::modules::core::context::Context;
+
+    #[tokio::test]
+    async fn should_not_find_split_of_unknown_route() {
+        // given:
+        let request = split_request("unknown", r#"{"percentage": 10}"#);
+
+        // when:
+        let status = call_api(request).await;
+
+        // then:
+        assert_eq!(404, status);
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_split_percentage() {
+        // given:
+        let request = split_request("unknown", r#"{"percentage": 101}"#);
+
+        // when:
+        let status = call_api(request).await;
+
+        // then:
+        assert_eq!(400, status);
+    }
+
+    fn split_request(route_id: &str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(Method::PUT)
+            .uri(format!("/routes/{}/split", route_id))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    /// Sends the given request to the API, backed by an empty core, and returns the status code
+    async fn call_api(request: Request<Body>) -> u16 {
+        let (send_cmd, recv_cmd) = broadcast::channel(16);
+        let (send_evt, recv_evt) = broadcast::channel(16);
+        tokio::spawn(empty_core(recv_cmd, send_evt));
+
+        let response = handle_api(request, send_cmd, recv_evt).await.unwrap();
+        response.status().as_u16()
+    }
+
+    /// Sets traffic splits on a context without routes
+    async fn empty_core(mut recv_cmd: Receiver<Command>, send_evt: Sender<Event>) {
+        let mut context = Context::build_empty();
+        while let Ok(command) = recv_cmd.recv().await {
+            if let Command::SetTrafficSplit {
+                id,
+                route_id,
+                percentage,
+            } = command
+            {
+                let event = match context.set_traffic_split(&route_id, percentage) {
+                    Ok(route) => Event::TrafficSplitWasSet {
+                        cmd_id: id,
+                        route: route.clone(),
+                    },
+                    Err(error) => Event::TrafficSplitWasNotSet { cmd_id: id, error },
+                };
+                send_evt.send(event).unwrap();
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::mem::size_of;
 use std::net::SocketAddr;
+use std::sync::Arc;
 
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
@@ -9,11 +10,14 @@ use tokio::sync::broadcast;
 use crate::errors::HapiError;
 use crate::events::commands::Command;
 use crate::events::events::Event;
+use crate::infrastructure::breaker_handler::handle_breakers;
 use crate::infrastructure::core_handler::handle_core;
+use crate::infrastructure::outlier_handler::handle_outliers;
 use crate::infrastructure::probe_handler::handle_probes;
 use crate::infrastructure::processor::process_request;
 use crate::infrastructure::settings::HapiSettings;
 use crate::infrastructure::stats_handler::handle_stats;
+use crate::infrastructure::upstream_client::UpstreamClients;
 use crate::interfaces::api::handle_api;
 
 mod errors;
@@ -27,6 +31,7 @@ mod repositories;
 async fn main() -> Result<(), HapiError> {
     simple_logger::init_with_env()?;
     log::info!("This is Hapi, the Happy API");
+    let settings = Arc::new(HapiSettings::load_from_file("settings.json")?);
 
     // commands channel
     let (send_cmd, _recv_cmd) = broadcast::channel(1024 * size_of::<Command>());
@@ -55,24 +60,56 @@ async fn main() -> Result<(), HapiError> {
         handle_probes(recv_evt3, send_cmd3).await;
     });
 
+    // circuit breakers handler
+    let recv_cmd6 = send_cmd.subscribe();
+    let send_cmd6 = send_cmd.clone();
+    let send_evt6 = send_evt.clone();
+    let breaker_settings = settings.circuit_breaker.clone();
+    tokio::spawn(async move {
+        handle_breakers(recv_cmd6, send_cmd6, send_evt6, breaker_settings).await;
+    });
+
+    // outlier detection handler
+    let recv_cmd7 = send_cmd.subscribe();
+    let recv_evt7 = send_evt.subscribe();
+    let send_cmd7 = send_cmd.clone();
+    let send_evt7 = send_evt.clone();
+    let outlier_settings = settings.outlier_detection.clone();
+    tokio::spawn(async move {
+        handle_outliers(recv_cmd7, recv_evt7, send_cmd7, send_evt7, outlier_settings).await;
+    });
+
     let send_cmd4 = send_cmd.clone();
     let send_evt4 = send_evt.clone();
+    let settings4 = settings.clone();
+    let upstream_clients4 = UpstreamClients::build(&settings.upstream_client);
     let make_service = make_service_fn(move |conn: &AddrStream| {
         let remote_addr = conn.remote_addr();
         let send_cmd4 = send_cmd4.clone();
         let send_evt4 = send_evt4.clone();
+        let settings4 = settings4.clone();
+        let upstream_clients4 = upstream_clients4.clone();
 
         let service = service_fn(move |request| {
             let client = identify_client(&remote_addr, &request);
             let send_cmd4 = send_cmd4.clone();
             let send_evt4 = send_evt4.clone();
             let recv_evt4 = send_evt4.subscribe();
-            process_request(request, client, send_cmd4, recv_evt4)
+            let settings4 = settings4.clone();
+            let upstream_clients4 = upstream_clients4.clone();
+            process_request(
+                request,
+                client,
+                remote_addr,
+                settings4,
+                upstream_clients4,
+                send_cmd4,
+                recv_evt4,
+            )
         });
         async move { Ok::<_, HapiError>(service) }
     });
 
-    let settings = HapiSettings::load_from_file("settings.json")?;
     let addr = settings.server_socket_address()?;
     let server = Server::bind(&addr)
         .serve(make_service)
@@ -0,0 +1,279 @@
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum CircuitState {
+    Closed,   // requests flow to the upstream
+    Open,     // no requests go to the upstream until the cool-down ends
+    HalfOpen, // a few trial requests go to the upstream to decide whether to close the circuit
+}
+
+impl Display for CircuitState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CircuitState::Closed => write!(f, "closed"),
+            CircuitState::Open => write!(f, "open"),
+            CircuitState::HalfOpen => write!(f, "half_open"),
+        }
+    }
+}
+
+/// Circuit breaker of an upstream, fed by the outcomes of the requests forwarded to it. The
+/// circuit opens after a number of consecutive failures, or once the failure rate of the last
+/// requests reaches a threshold. Whoever owns the breaker half-opens it after a cool-down, and then
+/// the outcomes of the trial requests decide whether it closes again or opens once more
+pub(crate) struct CircuitBreaker {
+    consecutive_failures: u64,
+    error_rate: f64,
+    min_requests: usize,
+    window: usize,
+    trial_requests: usize,
+    state: CircuitState,
+    current_consecutive_failures: u64,
+    recent_failures: VecDeque<bool>, // outcomes of the last `window` requests, `true` if failed
+    current_trial_successes: usize,
+}
+
+impl CircuitBreaker {
+    pub fn build(
+        consecutive_failures: u64,
+        error_rate: f64,
+        min_requests: usize,
+        window: usize,
+        trial_requests: usize,
+    ) -> Self {
+        CircuitBreaker {
+            consecutive_failures,
+            error_rate,
+            min_requests,
+            window,
+            trial_requests,
+            state: CircuitState::Closed,
+            current_consecutive_failures: 0,
+            recent_failures: VecDeque::with_capacity(window),
+            current_trial_successes: 0,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Records the outcome of a request to the upstream. Returns the new state of the circuit, if
+    /// it changed
+    pub fn record(&mut self, failed: bool) -> Option<CircuitState> {
+        match self.state {
+            CircuitState::Closed => {
+                if failed {
+                    self.current_consecutive_failures += 1;
+                } else {
+                    self.current_consecutive_failures = 0;
+                }
+                if self.recent_failures.len() == self.window {
+                    self.recent_failures.pop_front();
+                }
+                self.recent_failures.push_back(failed);
+
+                if self.current_consecutive_failures >= self.consecutive_failures
+                    || self.reached_error_rate()
+                {
+                    return self.change_to(CircuitState::Open);
+                }
+                None
+            }
+            CircuitState::HalfOpen => {
+                if failed {
+                    return self.change_to(CircuitState::Open);
+                }
+                self.current_trial_successes += 1;
+                if self.current_trial_successes >= self.trial_requests {
+                    return self.change_to(CircuitState::Closed);
+                }
+                None
+            }
+            CircuitState::Open => None, // requests sent before the circuit opened
+        }
+    }
+
+    /// Records a request to the upstream that was abandoned before it got an outcome, such as
+    /// when the client goes away. Only half-open circuits care, since they can't close unless
+    /// every trial request succeeds, so they open again. Returns the new state of the circuit, if
+    /// it changed
+    pub fn record_abandoned(&mut self) -> Option<CircuitState> {
+        match self.state {
+            CircuitState::HalfOpen => self.change_to(CircuitState::Open),
+            _ => None,
+        }
+    }
+
+    /// Lets trial requests through an open circuit. Returns `true` if the circuit was open
+    pub fn half_open(&mut self) -> bool {
+        if self.state == CircuitState::Open {
+            self.change_to(CircuitState::HalfOpen);
+            return true;
+        }
+        false
+    }
+
+    fn reached_error_rate(&self) -> bool {
+        let requests = self.recent_failures.len();
+        let failures = self
+            .recent_failures
+            .iter()
+            .filter(|failed| **failed)
+            .count();
+        requests > 0
+            && requests >= self.min_requests
+            && failures as f64 / requests as f64 >= self.error_rate
+    }
+
+    fn change_to(&mut self, state: CircuitState) -> Option<CircuitState> {
+        self.state = state;
+        self.current_consecutive_failures = 0;
+        self.recent_failures.clear();
+        self.current_trial_successes = 0;
+        Some(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::modules::breaker::{CircuitBreaker, CircuitState};
+
+    #[test]
+    fn should_open_circuit_if_reached_consecutive_failures() {
+        // given:
+        let mut breaker = CircuitBreaker::build(3, 1.0, 100, 100, 2);
+        breaker.record(true);
+        breaker.record(true);
+
+        // when:
+        let result = breaker.record(true);
+
+        // then:
+        assert_eq!(Some(CircuitState::Open), result);
+        assert_eq!(CircuitState::Open, breaker.state());
+    }
+
+    #[test]
+    fn should_not_open_circuit_if_failures_are_not_consecutive() {
+        // given:
+        let mut breaker = CircuitBreaker::build(3, 1.0, 100, 100, 2);
+
+        // when:
+        let results: Vec<Option<CircuitState>> = [true, true, false, true, true]
+            .iter()
+            .map(|failed| breaker.record(*failed))
+            .collect();
+
+        // then:
+        assert!(results.iter().all(|result| result.is_none()));
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[test]
+    fn should_open_circuit_if_reached_error_rate() {
+        // given:
+        let mut breaker = CircuitBreaker::build(10, 0.5, 4, 4, 2);
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(true);
+
+        // when:
+        let result = breaker.record(false);
+
+        // then:
+        assert_eq!(Some(CircuitState::Open), result);
+    }
+
+    #[test]
+    fn should_not_open_circuit_below_min_requests() {
+        // given:
+        let mut breaker = CircuitBreaker::build(10, 0.5, 4, 4, 2);
+        breaker.record(true);
+        breaker.record(false);
+
+        // when:
+        let result = breaker.record(true);
+
+        // then:
+        assert_eq!(None, result);
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[test]
+    fn should_only_consider_last_requests_for_error_rate() {
+        // given:
+        let mut breaker = CircuitBreaker::build(10, 0.75, 4, 4, 2);
+        for _ in 0..4 {
+            breaker.record(false);
+        }
+        breaker.record(true);
+        breaker.record(true);
+
+        // when:
+        let result = breaker.record(true);
+
+        // then:
+        assert_eq!(Some(CircuitState::Open), result);
+    }
+
+    #[test]
+    fn should_close_circuit_if_trial_requests_succeed() {
+        // given:
+        let mut breaker = CircuitBreaker::build(1, 1.0, 100, 100, 2);
+        breaker.record(true);
+        let half_opened = breaker.half_open();
+
+        // when:
+        let first = breaker.record(false);
+        let second = breaker.record(false);
+
+        // then:
+        assert!(half_opened);
+        assert_eq!(None, first);
+        assert_eq!(Some(CircuitState::Closed), second);
+    }
+
+    #[test]
+    fn should_open_circuit_again_if_trial_request_fails() {
+        // given:
+        let mut breaker = CircuitBreaker::build(1, 1.0, 100, 100, 2);
+        breaker.record(true);
+        breaker.half_open();
+        breaker.record(false);
+
+        // when:
+        let result = breaker.record(true);
+
+        // then:
+        assert_eq!(Some(CircuitState::Open), result);
+    }
+
+    #[test]
+    fn should_open_circuit_again_if_trial_request_is_abandoned() {
+        // given:
+        let mut breaker = CircuitBreaker::build(1, 1.0, 100, 100, 2);
+        breaker.record(true);
+        breaker.half_open();
+
+        // when:
+        let result = breaker.record_abandoned();
+
+        // then:
+        assert_eq!(Some(CircuitState::Open), result);
+    }
+
+    #[test]
+    fn should_not_half_open_closed_circuit() {
+        // given:
+        let mut breaker = CircuitBreaker::build(1, 1.0, 100, 100, 2);
+
+        // when:
+        let result = breaker.half_open();
+
+        // then:
+        assert!(!result);
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+}
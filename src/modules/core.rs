@@ -1,70 +1,319 @@
 pub(crate) mod context {
-    use crate::modules::core::route::Route;
-    use crate::modules::core::upstream::{Upstream, UpstreamAddress};
-    use regex::Regex;
+    use crate::modules::core::route::{
+        HostPattern, PathParams, PathTemplate, PredicateCondition, PredicateSource,
+        RequestPredicate, RetryPolicy, RewriteRule, Route, UpstreamTimeouts,
+    };
+    use crate::modules::core::upstream::{
+        HashKey, InFlightRequests, Upstream, UpstreamAddress, UpstreamStrategy,
+    };
+    use regex::{Regex, RegexSet, SetMatches};
+    use std::cmp::Reverse;
     use std::collections::{HashMap, HashSet};
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+
+    // '.' is deliberately left out: it is far more common as a literal in paths (`/index.html`)
+    const REGEXP_METACHARACTERS: [char; 13] =
+        ['^', '$', '*', '+', '?', '(', ')', '[', ']', '{', '}', '|', '\\'];
 
     #[derive(Clone, Debug)]
     pub(crate) struct Context {
         routes: Vec<Route>,
-        routing_table: HashMap<(String, String), usize>, // (path, method) => route index
+        route_matchers: Vec<Vec<RouteMatcher>>, // compiled matchers, same order as routes
+        route_rewriters: Vec<Vec<PathRewriter>>, // compiled rewrite rules, same order as routes
+        matchers: Vec<RouteMatcher>, // one per (route, path, method), sorted by precedence
+        scanned_positions: Vec<usize>, // positions of the matchers not in the routing table
+        regexp_set: Option<RegexSet>, // all regexp paths in matchers, built on first lookup
+        routing_table: HashMap<(String, String), Vec<usize>>, // (path, method) => matcher positions
         route_index: HashMap<String, usize>, // route id => route index
+        in_flight: InFlightRequests, // upstream => requests looked up but not completed yet
+        circuits: HashMap<UpstreamAddress, usize>, // upstream => trial requests left, if not closed
+        ejections: HashMap<String, Vec<UpstreamAddress>>, // route id => upstreams ejected from it
     }
 
     impl Context {
         pub fn build_empty() -> Self {
             Context {
                 routes: Vec::new(),
+                route_matchers: Vec::new(),
+                route_rewriters: Vec::new(),
+                matchers: Vec::new(),
+                scanned_positions: Vec::new(),
+                regexp_set: None,
                 routing_table: HashMap::new(),
                 route_index: HashMap::new(),
+                in_flight: InFlightRequests::new(),
+                circuits: HashMap::new(),
+                ejections: HashMap::new(),
             }
         }
 
-        /// Given a path and a method, attempts to get a proper route and returns an upstream that
-        /// is capable of handling the request.
-        /// First, try to get the route by matching exactly by (path, method). If that fails, try
-        /// to match by wrapping the given path and method using regular expressions
+        /// Given a request (host, path, method, headers and query), attempts to get a proper route
+        /// and returns an upstream that is capable of handling the request, along with the id of
+        /// the matched route and the parameters captured from its path template (if any).
+        /// The host may include a port, which is ignored. Routes without hosts match any host.
+        /// When several routes match, the one with the highest precedence wins (see
+        /// `rebuild_routing_table`).
+        /// Requests carrying the sticky cookie of the matched route go to the upstream it names
+        /// as long as that upstream is enabled, otherwise the route strategy picks one (or the
+        /// canary strategy, for the share of requests given to it by the route split).
+        /// The path to forward to the upstream is the request path after the route rewrite rules.
+        /// Upstreams excluded by the request, whose circuit is open or that were ejected from the
+        /// matched route are never returned.
+        /// Returns `None` if no route matches the request, and a `NoHealthyUpstream` error if the
+        /// matched route has no upstream left to return
         pub fn upstream_lookup(
             &mut self,
-            path: &str,
-            method: &str
-        ) -> Result<Option<&Upstream>, CoreError> {
-            let result = self.find_route_index(path, method)?
-                .and_then(move |route_index| self.routes.get_mut(route_index))
-                .and_then(|route| route.strategy.next());
-
+            request: &LookupRequest,
+        ) -> Result<Option<UpstreamMatch>, CoreError> {
+            self.build_regexp_set();
+            let mut excluded = self.excluded_upstreams_for(request);
+            let host = request.host.as_deref().map(HostPattern::normalize);
+            let result = self.find_route_index(host.as_deref(), request)?
+                .map(|(route_index, params)| {
+                    let route = &mut self.routes[route_index];
+                    let route_id = route.id.clone();
+                    if let Some(ejected) = self.ejections.get(&route_id) {
+                        excluded.extend(ejected.iter().cloned());
+                    }
+                    let sticky_token = route
+                        .sticky_cookie
+                        .as_deref()
+                        .and_then(|name| request.cookie(name));
+                    let preferred = sticky_token.and_then(|token| {
+                        route
+                            .upstreams()
+                            .into_iter()
+                            .find(|u| u.enabled && u.address.affinity_token() == token)
+                            .filter(|u| !excluded.contains(&u.address))
+                            .map(|u| u.address.clone())
+                    });
+                    let upstream_address = match preferred {
+                        Some(address) => address,
+                        None => {
+                            let in_flight = &self.in_flight;
+                            let next_of = |strategy: &mut UpstreamStrategy| {
+                                let hash_key =
+                                    strategy.hash_key().and_then(|key| request.hash_key(key));
+                                strategy.next_excluding(in_flight, hash_key, &excluded)
+                            };
+                            // canary requests fall back to the route strategy if need be
+                            let canary = match route.split.as_mut() {
+                                Some(split) => {
+                                    let picked = split.picks_canary(&request.client);
+                                    picked.then_some(&mut split.canary)
+                                }
+                                None => None,
+                            };
+                            canary
+                                .and_then(next_of)
+                                .or_else(|| next_of(&mut route.strategy))
+                                .ok_or_else(|| CoreError::NoHealthyUpstream(route_id.clone()))?
+                        }
+                    };
+                    // (re)issue the cookie unless the request already sticks to this upstream
+                    let affinity_cookie = route
+                        .sticky_cookie
+                        .as_ref()
+                        .map(|name| (name.clone(), upstream_address.affinity_token()))
+                        .filter(|(_, token)| sticky_token != Some(token.as_str()));
+
+                    let upstream_path = self.route_rewriters[route_index]
+                        .iter()
+                        .fold(request.path.clone(), |path, rewriter| rewriter.apply(&path));
+
+                    Ok(UpstreamMatch {
+                        route_id,
+                        upstream_address,
+                        upstream_path,
+                        params,
+                        affinity_cookie,
+                        sticky_cookie: route.sticky_cookie.clone(),
+                        mirror: route.mirror.clone(),
+                        timeouts: route.timeouts.clone(),
+                        retry: route.retry.clone(),
+                    })
+                })
+                .transpose()?;
+
+            if let Some(upstream) = &result {
+                *self.in_flight.entry(upstream.upstream_address.clone()).or_default() += 1;
+                if let Some(trial_requests) = self.circuits.get_mut(&upstream.upstream_address) {
+                    *trial_requests -= 1;
+                }
+            }
             Ok(result)
         }
 
+        /// Opens the circuit of the given upstream, so no requests get routed to it until its
+        /// circuit is half-opened or closed. Unlike disabling, this is driven by the outcome of
+        /// the requests, and leaves the upstream enabled as far as probes are concerned.
+        /// Returns the failover routes whose active tier changed as a result
+        pub fn open_circuit(
+            &mut self,
+            upstream: &UpstreamAddress,
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let from_tiers = self.active_tiers();
+            self.circuits.insert(upstream.clone(), 0);
+            Ok(self.tier_changes_since(from_tiers))
+        }
+
+        /// Lets the given number of trial requests through the circuit of the given upstream.
+        /// Failover routes stay on their tier until the circuit closes
+        pub fn half_open_circuit(
+            &mut self,
+            upstream: &UpstreamAddress,
+            trial_requests: usize,
+        ) -> Result<(), CoreError> {
+            self.circuits.insert(upstream.clone(), trial_requests);
+            Ok(())
+        }
+
+        /// Closes the circuit of the given upstream, so requests get routed to it again.
+        /// Returns the failover routes whose active tier changed as a result
+        pub fn close_circuit(
+            &mut self,
+            upstream: &UpstreamAddress,
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let from_tiers = self.active_tiers();
+            self.circuits.remove(upstream);
+            Ok(self.tier_changes_since(from_tiers))
+        }
+
+        /// Ejects the given upstream from the given route, so the route doesn't send requests to
+        /// it until it is restored. Other routes keep using it
+        /// Returns the failover route change of active tier, if any, or an error if the route
+        /// doesn't exist or doesn't contain the upstream
+        pub fn eject_upstream(
+            &mut self,
+            route_id: &str,
+            upstream: &UpstreamAddress,
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let route_index = *self.route_index.get(route_id).ok_or(CoreError::RouteNotExists)?;
+            if !self.routes[route_index].upstreams().iter().any(|u| u.address == *upstream) {
+                return Err(CoreError::UpstreamNotExists);
+            }
+            let from_tiers = self.active_tiers();
+            let ejected = self.ejections.entry(route_id.to_string()).or_default();
+            if !ejected.contains(upstream) {
+                ejected.push(upstream.clone());
+            }
+            Ok(self.tier_changes_since(from_tiers))
+        }
+
+        /// Brings back an upstream previously ejected from the given route
+        /// Returns the failover route change of active tier, if any
+        pub fn restore_upstream(
+            &mut self,
+            route_id: &str,
+            upstream: &UpstreamAddress,
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let from_tiers = self.active_tiers();
+            if let Some(ejected) = self.ejections.get_mut(route_id) {
+                ejected.retain(|u| u != upstream);
+                if ejected.is_empty() {
+                    self.ejections.remove(route_id);
+                }
+            }
+            Ok(self.tier_changes_since(from_tiers))
+        }
+
+        /// Tier serving each route, for failover routes. Upstreams whose circuit is not closed or
+        /// that were ejected from the route don't count as available
+        fn active_tiers(&self) -> Vec<Option<usize>> {
+            self.routes
+                .iter()
+                .map(|route| {
+                    let mut unavailable: Vec<UpstreamAddress> =
+                        self.circuits.keys().cloned().collect();
+                    if let Some(ejected) = self.ejections.get(&route.id) {
+                        unavailable.extend(ejected.iter().cloned());
+                    }
+                    route.strategy.active_tier_excluding(&unavailable)
+                })
+                .collect()
+        }
+
+        /// Compares the tier serving each route with the given former ones
+        fn tier_changes_since(&self, from_tiers: Vec<Option<usize>>) -> Vec<TierChange> {
+            self.routes
+                .iter()
+                .zip(from_tiers)
+                .zip(self.active_tiers())
+                .filter_map(|((route, from_tier), to_tier)| {
+                    TierChange::between(route, from_tier, to_tier)
+                })
+                .collect()
+        }
+
+        fn excluded_upstreams_for(&self, request: &LookupRequest) -> Vec<UpstreamAddress> {
+            let open_circuits = self
+                .circuits
+                .iter()
+                .filter(|(_, trial_requests)| **trial_requests == 0)
+                .map(|(upstream, _)| upstream.clone());
+            request.excluded_upstreams.iter().cloned().chain(open_circuits).collect()
+        }
+
+        /// Marks a request previously returned by `upstream_lookup` as completed, so it no longer
+        /// counts towards the load of the given upstream. The upstream latency, if the request
+        /// got a response, is recorded by the strategy of the given route
+        pub fn complete_request(
+            &mut self,
+            route_id: &str,
+            upstream: &UpstreamAddress,
+            latency: Option<Duration>,
+        ) -> Result<(), CoreError> {
+            if let Some(count) = self.in_flight.get_mut(upstream) {
+                *count -= 1;
+                if *count == 0 {
+                    self.in_flight.remove(upstream);
+                }
+            }
+            if let (Some(latency), Some(route_index)) = (latency, self.route_index.get(route_id)) {
+                for strategy in self.routes[*route_index].strategies_mut() {
+                    strategy.record_latency(upstream, latency);
+                }
+            }
+            Ok(())
+        }
+
         /// Disables the given upstream from all the routes that contain it, so new requests don't
-        /// get routed to that upstream
+        /// get routed to that upstream.
+        /// Returns the failover routes whose active tier changed as a result
         pub fn disable_upstream_for_all_routes(
             &mut self,
             upstream: &UpstreamAddress,
-        ) -> Result<(), CoreError> {
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let from_tiers = self.active_tiers();
             for route in self.routes.iter_mut() {
-                route.strategy.disable_upstream(upstream)
+                route.strategies_mut().for_each(|strategy| strategy.disable_upstream(upstream));
             }
-            Ok(())
+            Ok(self.tier_changes_since(from_tiers))
         }
 
         /// Enables the given upstream in all the routes that contain it, so new requests can be
-        /// routed to that upstream
+        /// routed to that upstream.
+        /// Returns the failover routes whose active tier changed as a result
         pub fn enable_upstream_for_all_routes(
             &mut self,
             upstream: &UpstreamAddress,
-        ) -> Result<(), CoreError> {
+        ) -> Result<Vec<TierChange>, CoreError> {
+            let from_tiers = self.active_tiers();
             for route in self.routes.iter_mut() {
-                route.strategy.enable_upstream(upstream)
+                route.strategies_mut().for_each(|strategy| strategy.enable_upstream(upstream));
             }
-            Ok(())
+            Ok(self.tier_changes_since(from_tiers))
         }
 
         /// Adds the given route to this context
-        /// Returns an error if the given route already exists in the context
+        /// Returns an error if the given route already exists in the context or if any of its
+        /// paths, methods or rewrite patterns is not a valid regular expression
         pub fn add_route(&mut self, route: Route) -> Result<(), CoreError> {
             if !self.route_index.contains_key(&route.id) {
-                self.do_add_route(route);
+                let compiled_matchers = compile_matchers(&route)?;
+                let compiled_rewriters = compile_rewriters(&route)?;
+                self.do_add_route(route, compiled_matchers, compiled_rewriters);
                 Ok(())
             } else {
                 Err(CoreError::RouteAlreadyExists)
@@ -77,6 +326,7 @@ pub(crate) mod context {
             match self.route_index.get(route_id) {
                 Some(route_index) => {
                     let removed_route = self.do_remove_route(*route_index);
+                    self.ejections.remove(route_id);
                     Ok(removed_route)
                 }
                 None => Err(CoreError::RouteNotExists),
@@ -85,15 +335,18 @@ pub(crate) mod context {
 
         pub fn get_all_upstreams(&self) -> Result<Vec<&Upstream>, CoreError> {
             let mut temp = HashSet::new();
+            let mut result = Vec::new();
 
+            // the same upstream may carry a different weight on each route
             for route in self.routes.iter() {
-                let ups = route.strategy.get_upstreams();
+                let ups = route.upstreams();
                 for u in ups {
-                    temp.insert(u);
+                    if temp.insert(&u.address) {
+                        result.push(u);
+                    }
                 }
             }
 
-            let result = temp.into_iter().collect();
             Ok(result)
         }
 
@@ -113,42 +366,148 @@ pub(crate) mod context {
             Ok(route)
         }
 
+        /// Changes the percentage of requests the given route sends to its canary upstreams
+        /// Returns an error if the route doesn't exist, has no canary upstreams or the percentage
+        /// is over 100
+        pub fn set_traffic_split(
+            &mut self,
+            route_id: &str,
+            percentage: u8,
+        ) -> Result<&Route, CoreError> {
+            if percentage > 100 {
+                let reason = format!("{} is not a percentage", percentage);
+                return Err(CoreError::InvalidTrafficSplit(reason));
+            }
+            let route_index = *self.route_index.get(route_id).ok_or(CoreError::RouteNotExists)?;
+            let route = &mut self.routes[route_index];
+            match route.split.as_mut() {
+                Some(split) => {
+                    split.percentage = percentage;
+                    split.credit = 0;
+                    Ok(route)
+                }
+                None => {
+                    let reason = format!("route {} has no canary upstreams", route_id);
+                    Err(CoreError::InvalidTrafficSplit(reason))
+                }
+            }
+        }
+
+        /// Returns the ids of the existing routes that completely shadow the given route, that is,
+        /// routes with higher precedence that match every (path, method) pair the given route
+        /// would match. Returns an empty vector if the route is reachable for at least one pair.
+        /// The check is conservative: a regular expression path or method is only considered to
+        /// be shadowed by an identical one
+        pub fn find_shadowing_routes(&self, route_id: &str) -> Result<Vec<String>, CoreError> {
+            let route_index = *self.route_index
+                .get(route_id)
+                .ok_or(CoreError::RouteNotExists)?;
+            let mut result: Vec<String> = Vec::new();
+
+            for (position, shadowed) in self.matchers.iter().enumerate() {
+                if shadowed.route_index != route_index {
+                    continue;
+                }
+
+                let shadowing = self.matchers[..position]
+                    .iter()
+                    .find(|m| m.route_index != route_index && m.covers(shadowed));
+                match shadowing {
+                    Some(m) => {
+                        let id = &self.routes[m.route_index].id;
+                        if !result.contains(id) {
+                            result.push(id.clone());
+                        }
+                    }
+                    None => return Ok(Vec::new()),
+                }
+            }
+            Ok(result)
+        }
+
+        /// Returns the route index of the matcher with the highest precedence for the given path
+        /// and method. Exact matches are resolved through the routing table, so only the
+        /// matchers that precede the best exact match need to be evaluated. Regular expression
+        /// paths are all evaluated at once through the regexp set
         fn find_route_index(
             &self,
-            path: &str,
-            method: &str
-        ) -> Result<Option<usize>, CoreError> {
-            let key = (path.to_string(), method.to_string());
-            let route_index = self.routing_table
+            host: Option<&str>,
+            request: &LookupRequest,
+        ) -> Result<Option<(usize, PathParams)>, CoreError> {
+            let path = request.path.as_str();
+            let key = (request.path.clone(), request.method.clone());
+            let exact_position = self.routing_table
                 .get(&key)
-                .map(|value| *value)
-                .or_else(|| { self.match_route_index(path, method).ok()? });
+                .and_then(|positions| {
+                    positions
+                        .iter()
+                        .find(|p| self.matchers[**p].accepts(host, request))
+                        .copied()
+                });
+            let limit = exact_position.unwrap_or(self.matchers.len());
+            let mut regexp_matches: Option<SetMatches> = None;
+
+            for position in self.scanned_positions.iter().take_while(|p| **p < limit) {
+                let matcher = &self.matchers[*position];
+                if !matcher.accepts(host, request) {
+                    continue;
+                }
+
+                let maybe_params = match (&matcher.path_kind, &self.regexp_set) {
+                    (PathKind::Regexp { set_index: Some(index), .. }, Some(set)) => {
+                        let matches = regexp_matches.get_or_insert_with(|| set.matches(path));
+                        matches.matched(*index).then(PathParams::new)
+                    }
+                    _ => matcher.captures_path(path),
+                };
+                if let Some(params) = maybe_params {
+                    return Ok(Some((matcher.route_index, params)));
+                }
+            }
 
-            Ok(route_index)
+            let result = exact_position
+                .map(|position| (self.matchers[position].route_index, PathParams::new()));
+            Ok(result)
         }
 
-        fn match_route_index(
-            &self,
-            path: &str,
-            method: &str
-        ) -> Result<Option<usize>, regex::Error> {
-            let mut result = Ok(None);
+        /// Builds the regexp set for the regular expression paths, unless it's already built.
+        /// If the set can't be built (e.g. it's too big), lookups fall back to evaluating each
+        /// regular expression on its own
+        fn build_regexp_set(&mut self) {
+            if self.regexp_set.is_some() {
+                return;
+            }
 
-            for (key, value) in self.routing_table.iter() {
-                let k = key.clone();
-                let path_regexp = Regex::new(regexp_for(k.0).as_str())?;
-                let method_regexp = Regex::new(regexp_for(k.1).as_str())?;
+            let mut patterns = Vec::new();
+            for matcher in self.matchers.iter_mut() {
+                if let PathKind::Regexp { regexp, set_index } = &mut matcher.path_kind {
+                    *set_index = Some(patterns.len());
+                    patterns.push(regexp.as_str().to_string());
+                }
+            }
 
-                if path_regexp.is_match(path) && method_regexp.is_match(method) {
-                    result = Ok(Some(*value));
-                    break;
+            match RegexSet::new(patterns) {
+                Ok(set) => self.regexp_set = Some(set),
+                Err(error) => {
+                    log::warn!("Could not build regexp set for routes: {:?}", error);
+                    for matcher in self.matchers.iter_mut() {
+                        if let PathKind::Regexp { set_index, .. } = &mut matcher.path_kind {
+                            *set_index = None;
+                        }
+                    }
                 }
             }
-            result
         }
 
-        fn do_add_route(&mut self, route: Route) {
+        fn do_add_route(
+            &mut self,
+            route: Route,
+            compiled_matchers: Vec<RouteMatcher>,
+            compiled_rewriters: Vec<PathRewriter>,
+        ) {
             self.routes.push(route);
+            self.route_matchers.push(compiled_matchers);
+            self.route_rewriters.push(compiled_rewriters);
 
             self.rebuild_routing_table();
             self.rebuild_route_index();
@@ -156,21 +515,59 @@ pub(crate) mod context {
 
         fn do_remove_route(&mut self, route_index: usize) -> Route {
             let removed_route = self.routes.remove(route_index);
+            self.route_matchers.remove(route_index);
+            self.route_rewriters.remove(route_index);
 
             self.rebuild_routing_table();
             self.rebuild_route_index();
             removed_route
         }
 
+        /// Rebuilds the route matchers sorted by precedence, along with the routing table for
+        /// exact (path, method) matches. Precedence is defined by, in order:
+        /// - the route priority (higher first)
+        /// - the kind of host: exact hosts, then wildcard hosts (longer first), then any host
+        /// - the kind of path: exact paths, then path templates, then regular expressions
+        /// - the path specificity (longer or with more literal segments first)
+        /// - the number of header and query predicates (more first)
+        /// - exact methods before regular expression methods
+        /// - the order in which the routes were added
+        ///
+        /// Matchers are compiled once when the route is added, so rebuilding only sorts them
         fn rebuild_routing_table(&mut self) {
+            self.matchers.clear();
+            self.scanned_positions.clear();
+            self.regexp_set = None;
             self.routing_table.clear();
 
-            for (index, route) in self.routes.iter().enumerate() {
-                for path in route.paths.iter() {
-                    for method in route.methods.iter() {
-                        self.routing_table
-                            .insert((path.clone(), method.clone()), index);
-                    }
+            for (index, compiled_matchers) in self.route_matchers.iter().enumerate() {
+                for matcher in compiled_matchers.iter() {
+                    let mut matcher = matcher.clone();
+                    matcher.route_index = index;
+                    self.matchers.push(matcher);
+                }
+            }
+
+            let routes = &self.routes;
+            self.matchers.sort_by_key(|m| {
+                (
+                    Reverse(routes[m.route_index].priority),
+                    m.host.rank(),
+                    m.path_kind.rank(),
+                    Reverse(m.specificity()),
+                    Reverse(m.predicates.len()),
+                    matches!(m.method_kind, MethodKind::Regexp(_)),
+                )
+            });
+
+            for (position, matcher) in self.matchers.iter().enumerate() {
+                if matcher.is_exact() {
+                    self.routing_table
+                        .entry((matcher.path.clone(), matcher.method.clone()))
+                        .or_default()
+                        .push(position);
+                } else {
+                    self.scanned_positions.push(position);
                 }
             }
         }
@@ -184,10 +581,405 @@ pub(crate) mod context {
         }
     }
 
+    /// Attributes of an incoming request that routes are matched against
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub(crate) struct LookupRequest {
+        pub client: String, // identifies the client, see `identify_client`
+        pub host: Option<String>,
+        pub path: String,
+        pub method: String,
+        pub headers: Vec<(String, String)>, // (name, value), one entry per header value
+        pub query: Vec<(String, String)>, // decoded (name, value) query parameters
+        pub excluded_upstreams: Vec<UpstreamAddress>, // upstreams that already failed the request
+    }
+
+    impl LookupRequest {
+        pub fn build(path: &str, method: &str) -> Self {
+            LookupRequest {
+                path: path.to_string(),
+                method: method.to_string(),
+                ..Default::default()
+            }
+        }
+
+        /// Returns the value of the given cookie, if the request carries it
+        pub fn cookie(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case("cookie"))
+                .flat_map(|(_, v)| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v)
+        }
+
+        /// Returns the key the given consistent hash strategy should hash, if the request has one.
+        /// Requests without the configured header or cookie have none, and are balanced as usual
+        fn hash_key(&self, key: &HashKey) -> Option<&str> {
+            let value = match key {
+                HashKey::Client => Some(self.client.as_str()),
+                HashKey::Header(name) => self
+                    .headers
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.as_str()),
+                HashKey::Cookie(name) => self.cookie(name),
+            };
+            value.filter(|value| !value.is_empty())
+        }
+
+        fn values_of(&self, source: &PredicateSource) -> Vec<&str> {
+            match source {
+                PredicateSource::Header(name) => self
+                    .headers
+                    .iter()
+                    .filter(|(n, _)| n.eq_ignore_ascii_case(name))
+                    .map(|(_, v)| v.as_str())
+                    .collect(),
+                PredicateSource::Query(name) => self
+                    .query
+                    .iter()
+                    .filter(|(n, _)| n == name)
+                    .map(|(_, v)| v.as_str())
+                    .collect(),
+            }
+        }
+    }
+
+    /// Outcome of a successful upstream lookup
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct UpstreamMatch {
+        pub route_id: String,
+        pub upstream_address: UpstreamAddress,
+        pub upstream_path: String, // request path after the route rewrite rules
+        pub params: PathParams,
+        pub affinity_cookie: Option<(String, String)>, // (name, value) to set on the response
+        pub sticky_cookie: Option<String>, // name of the route affinity cookie, kept from upstreams
+        pub mirror: Option<UpstreamAddress>, // shadow upstream getting a copy of the request
+        pub timeouts: UpstreamTimeouts,
+        pub retry: Option<RetryPolicy>,
+    }
+
+    /// Change of the tier serving a failover route. A tier is `None` when no tier has enabled
+    /// upstreams
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct TierChange {
+        pub route_id: String,
+        pub from_tier: Option<usize>,
+        pub to_tier: Option<usize>,
+    }
+
+    impl TierChange {
+        fn between(
+            route: &Route,
+            from_tier: Option<usize>,
+            to_tier: Option<usize>,
+        ) -> Option<Self> {
+            (from_tier != to_tier).then(|| TierChange {
+                route_id: route.id.clone(),
+                from_tier,
+                to_tier,
+            })
+        }
+
+        /// Whether traffic moved back to a more preferred tier
+        pub fn is_failback(&self) -> bool {
+            match (self.from_tier, self.to_tier) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(from), Some(to)) => to < from,
+            }
+        }
+    }
+
     #[derive(Clone, Debug)]
     pub(crate) enum CoreError {
         RouteAlreadyExists,
         RouteNotExists,
+        UpstreamNotExists,
+        InvalidRoutePattern(String),
+        InvalidTrafficSplit(String),
+        NoHealthyUpstream(String), // id of the matched route
+    }
+
+    impl Display for CoreError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            match self {
+                CoreError::RouteAlreadyExists => write!(f, "Route already exists"),
+                CoreError::RouteNotExists => write!(f, "Route does not exist"),
+                CoreError::UpstreamNotExists => write!(f, "Upstream does not exist"),
+                CoreError::InvalidRoutePattern(error) => {
+                    write!(f, "Invalid route pattern: {}", error)
+                }
+                CoreError::InvalidTrafficSplit(error) => {
+                    write!(f, "Invalid traffic split: {}", error)
+                }
+                CoreError::NoHealthyUpstream(route_id) => {
+                    write!(f, "No healthy upstream for route {}", route_id)
+                }
+            }
+        }
+    }
+
+    /// Matches a single (host, path, method) combination of a route
+    #[derive(Clone, Debug)]
+    struct RouteMatcher {
+        route_index: usize,
+        host: HostPattern,
+        path: String,
+        method: String,
+        path_kind: PathKind,
+        method_kind: MethodKind,
+        predicates: Vec<PredicateMatcher>,
+    }
+
+    #[derive(Clone, Debug)]
+    enum PathKind {
+        Exact,
+        Template(PathTemplate),
+        Regexp {
+            regexp: Regex,
+            set_index: Option<usize>, // position of this regexp in the context regexp set
+        },
+    }
+
+    #[derive(Clone, Debug)]
+    enum MethodKind {
+        Exact,
+        Regexp(Regex),
+    }
+
+    /// Compiled request predicate
+    #[derive(Clone, Debug)]
+    struct PredicateMatcher {
+        predicate: RequestPredicate,
+        regexp: Option<Regex>,
+    }
+
+    impl PredicateMatcher {
+        fn build(predicate: &RequestPredicate) -> Result<Self, regex::Error> {
+            let regexp = match &predicate.condition {
+                PredicateCondition::Matches(pattern) => {
+                    Some(Regex::new(regexp_for(pattern.clone()).as_str())?)
+                }
+                _ => None,
+            };
+            Ok(PredicateMatcher {
+                predicate: predicate.clone(),
+                regexp,
+            })
+        }
+
+        /// Headers or query parameters with several values match if any of the values does
+        fn matches(&self, request: &LookupRequest) -> bool {
+            let values = request.values_of(&self.predicate.source);
+            match (&self.predicate.condition, &self.regexp) {
+                (PredicateCondition::Equals(expected), _) => values.contains(&expected.as_str()),
+                (PredicateCondition::Matches(_), Some(regexp)) => {
+                    values.iter().any(|v| regexp.is_match(v))
+                }
+                (PredicateCondition::Matches(_), None) => false,
+                (PredicateCondition::Present, _) => !values.is_empty(),
+                (PredicateCondition::Absent, _) => values.is_empty(),
+            }
+        }
+    }
+
+    impl PathKind {
+        fn rank(&self) -> u8 {
+            match self {
+                PathKind::Exact => 0,
+                PathKind::Template(_) => 1,
+                PathKind::Regexp { .. } => 2,
+            }
+        }
+    }
+
+    impl RouteMatcher {
+        fn build(
+            route_index: usize,
+            host: HostPattern,
+            path: &str,
+            method: &str,
+            predicates: Vec<PredicateMatcher>,
+        ) -> Result<Self, regex::Error> {
+            let path_kind = match PathTemplate::parse(path) {
+                Some(template) => PathKind::Template(template),
+                None if is_literal(path) => PathKind::Exact,
+                None => PathKind::Regexp {
+                    regexp: Regex::new(regexp_for(path.to_string()).as_str())?,
+                    set_index: None,
+                },
+            };
+            let method_kind = if is_literal(method) {
+                MethodKind::Exact
+            } else {
+                MethodKind::Regexp(Regex::new(regexp_for(method.to_string()).as_str())?)
+            };
+
+            Ok(RouteMatcher {
+                route_index,
+                host,
+                path: path.to_string(),
+                method: method.to_string(),
+                path_kind,
+                method_kind,
+                predicates,
+            })
+        }
+
+        /// Returns `true` if the host, method and predicates match; the path is checked apart
+        fn accepts(&self, host: Option<&str>, request: &LookupRequest) -> bool {
+            self.host.matches(host)
+                && self.matches_method(request.method.as_str())
+                && self.predicates.iter().all(|p| p.matches(request))
+        }
+
+        fn is_exact(&self) -> bool {
+            matches!(
+                (&self.path_kind, &self.method_kind),
+                (PathKind::Exact, MethodKind::Exact)
+            )
+        }
+
+        fn specificity(&self) -> (usize, usize) {
+            match &self.path_kind {
+                PathKind::Template(template) => template.specificity(),
+                _ => (self.path.len(), 0),
+            }
+        }
+
+        fn captures_path(&self, path: &str) -> Option<PathParams> {
+            match &self.path_kind {
+                PathKind::Exact => (self.path == path).then(PathParams::new),
+                PathKind::Template(template) => template.captures(path),
+                PathKind::Regexp { regexp, .. } => regexp.is_match(path).then(PathParams::new),
+            }
+        }
+
+        fn matches_method(&self, method: &str) -> bool {
+            match &self.method_kind {
+                MethodKind::Exact => self.method == method,
+                MethodKind::Regexp(regexp) => regexp.is_match(method),
+            }
+        }
+
+        /// Returns `true` if every request matched by the other matcher is also matched by this one
+        fn covers(&self, other: &RouteMatcher) -> bool {
+            let path_covered = self.path == other.path
+                || (matches!(other.path_kind, PathKind::Exact)
+                    && self.captures_path(other.path.as_str()).is_some());
+            let method_covered = self.method == other.method
+                || (matches!(other.method_kind, MethodKind::Exact)
+                    && self.matches_method(other.method.as_str()));
+            let predicates_covered = self
+                .predicates
+                .iter()
+                .all(|p| other.predicates.iter().any(|o| o.predicate == p.predicate));
+            self.host.covers(&other.host) && path_covered && method_covered && predicates_covered
+        }
+    }
+
+    /// Compiles the matchers for every (host, path, method) combination of the given route, each
+    /// with the route predicates
+    fn compile_matchers(route: &Route) -> Result<Vec<RouteMatcher>, CoreError> {
+        let hosts = if route.hosts.is_empty() {
+            vec![HostPattern::Any]
+        } else {
+            let mut hosts = Vec::new();
+            for host in route.hosts.iter() {
+                let pattern = HostPattern::parse(host).ok_or_else(|| {
+                    CoreError::InvalidRoutePattern(format!("invalid host {}", host))
+                })?;
+                hosts.push(pattern);
+            }
+            hosts
+        };
+
+        let mut predicates = Vec::new();
+        for predicate in route.predicates.iter() {
+            let matcher = PredicateMatcher::build(predicate)
+                .map_err(|e| CoreError::InvalidRoutePattern(e.to_string()))?;
+            predicates.push(matcher);
+        }
+
+        let mut result = Vec::new();
+        for host in hosts.iter() {
+            for path in route.paths.iter() {
+                for method in route.methods.iter() {
+                    let matcher =
+                        RouteMatcher::build(0, host.clone(), path, method, predicates.clone())
+                            .map_err(|e| CoreError::InvalidRoutePattern(e.to_string()))?;
+                    result.push(matcher);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Compiled route rewrite rule
+    #[derive(Clone, Debug)]
+    enum PathRewriter {
+        StripPrefix(String), // without trailing slash
+        AddPrefix(String), // without trailing slash
+        Replace(Regex, String),
+    }
+
+    impl PathRewriter {
+        /// Added prefixes must start with `/`, so they can't merge with the upstream authority
+        fn build(rule: &RewriteRule) -> Result<Self, CoreError> {
+            let rewriter = match rule {
+                RewriteRule::StripPrefix(prefix) => {
+                    PathRewriter::StripPrefix(prefix.trim_end_matches('/').to_string())
+                }
+                RewriteRule::AddPrefix(prefix) if !prefix.starts_with('/') => {
+                    let error = format!("prefix {} does not start with /", prefix);
+                    return Err(CoreError::InvalidRoutePattern(error));
+                }
+                RewriteRule::AddPrefix(prefix) => {
+                    PathRewriter::AddPrefix(prefix.trim_end_matches('/').to_string())
+                }
+                RewriteRule::Replace { pattern, replacement } => {
+                    let regexp = Regex::new(pattern)
+                        .map_err(|e| CoreError::InvalidRoutePattern(e.to_string()))?;
+                    PathRewriter::Replace(regexp, replacement.clone())
+                }
+            };
+            Ok(rewriter)
+        }
+
+        /// Prefixes are only stripped at segment boundaries, so `/svc` is stripped from `/svc/a`
+        /// but not from `/svcs/a`. Paths always start with `/`, even if a replacement removed it
+        fn apply(&self, path: &str) -> String {
+            let result = match self {
+                PathRewriter::StripPrefix(prefix) => match path.strip_prefix(prefix.as_str()) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.to_string(),
+                    _ => path.to_string(),
+                },
+                PathRewriter::AddPrefix(prefix) => format!("{}{}", prefix, path),
+                PathRewriter::Replace(regexp, replacement) => {
+                    regexp.replace(path, replacement.as_str()).into_owned()
+                }
+            };
+            if result.starts_with('/') {
+                result
+            } else {
+                format!("/{}", result)
+            }
+        }
+    }
+
+    fn compile_rewriters(route: &Route) -> Result<Vec<PathRewriter>, CoreError> {
+        route
+            .rewrites
+            .iter()
+            .map(PathRewriter::build)
+            .collect()
+    }
+
+    /// Returns `true` if the given route path or method contains no regular expression syntax
+    fn is_literal(string: &str) -> bool {
+        !string.contains(REGEXP_METACHARACTERS)
     }
 
     fn regexp_for(string: String) -> String {
@@ -200,10 +992,16 @@ pub(crate) mod context {
 
     #[cfg(test)]
     mod tests {
-        use crate::modules::core::context::Context;
-        use crate::modules::core::route::Route;
-        use crate::modules::core::upstream::{Upstream, UpstreamAddress};
+        use crate::modules::core::context::{Context, CoreError, LookupRequest, TierChange};
+        use crate::modules::core::route::{
+            PredicateCondition, PredicateSource, RequestPredicate, RewriteRule, Route,
+            TrafficSplit,
+        };
+        use crate::modules::core::upstream::{
+            HashKey, Upstream, UpstreamAddress, UpstreamStrategy,
+        };
         use crate::modules::core::upstream::UpstreamStrategy::{AlwaysFirst, RoundRobin};
+        use std::time::Duration;
 
         #[test]
         fn should_perform_upstream_lookup() {
@@ -213,10 +1011,13 @@ pub(crate) mod context {
             context.add_route(sample_route_2_rr()).unwrap();
 
             // when:
-            let upstream = context.upstream_lookup("uri1", "GET").unwrap().unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("uri1", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            assert_eq!("upstream1", upstream.address.to_string().as_str());
+            assert_eq!("upstream1", upstream.upstream_address.to_string().as_str());
         }
 
         #[test]
@@ -227,12 +1028,15 @@ pub(crate) mod context {
             context.add_route(sample_route_3_af()).unwrap();
 
             // when:
-            let upstream = context.upstream_lookup("uri10", "GET").unwrap().unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("uri10", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
             assert_eq!(
                 "upstream20".to_string(),
-                upstream.address.to_string().as_str()
+                upstream.upstream_address.to_string().as_str()
             );
         }
 
@@ -244,123 +1048,1080 @@ pub(crate) mod context {
             context.add_route(sample_route_4_af()).unwrap();
 
             // when:
-            let upstream = context.upstream_lookup("uri4", "PATCH").unwrap().unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("uri4", "PATCH"))
+                .unwrap()
+                .unwrap();
 
             // then:
             assert_eq!(
                 "upstream10".to_string(),
-                upstream.address.to_string().as_str()
+                upstream.upstream_address.to_string().as_str()
             );
         }
 
         #[test]
-        fn should_not_find_route_for_non_exact_match() {
+        fn should_match_route_by_path_template() {
             // given:
             let mut context = Context::build_empty();
-            context.add_route(sample_route_5_af()).unwrap();
+            context.add_route(sample_route_2_af()).unwrap();
+            context.add_route(sample_route_9_af()).unwrap();
 
             // when:
-            let upstream = context.upstream_lookup("uri5", "GET").unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("/users/42", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            assert_eq!(upstream, None)
+            assert_eq!("upstream30", upstream.upstream_address.to_string().as_str());
+            assert_eq!("id9", upstream.route_id.as_str());
+            assert_eq!(vec![(String::from("id"), String::from("42"))], upstream.params);
         }
 
         #[test]
-        fn should_not_find_route_if_all_upstreams_are_disabled() {
+        fn should_match_prefix_route_by_path_template() {
             // given:
-            let mut route = sample_route_1_rr();
-            let addresses: Vec<UpstreamAddress> = route.strategy.get_upstreams().iter().map(|u| u.address.clone()).collect();
-            for a in addresses.iter() {
-                route.strategy.disable_upstream(a);
-            }
             let mut context = Context::build_empty();
-            context.add_route(route).unwrap();
+            context.add_route(sample_route_9_af()).unwrap();
 
             // when:
-            let upstream = context.upstream_lookup("uri1", "GET").unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("/static/css/main.css", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            assert_eq!(None, upstream)
+            assert_eq!("upstream30", upstream.upstream_address.to_string().as_str());
+            assert_eq!(
+                vec![(String::from("rest"), String::from("css/main.css"))],
+                upstream.params
+            );
         }
 
         #[test]
-        fn should_disable_upstream() {
+        fn should_prefer_exact_over_template_over_regexp() {
             // given:
             let mut context = Context::build_empty();
-            context.add_route(sample_route_5_af()).unwrap();
-            context.add_route(sample_route_6_af()).unwrap();
-            let ups_addr = UpstreamAddress::FQDN(String::from("upstream21"));
+            context.add_route(sample_route("regexp", vec!["^/users/.*$"], "upstream1")).unwrap();
+            context.add_route(sample_route("template", vec!["/users/{id}"], "upstream2")).unwrap();
+            context.add_route(sample_route("exact", vec!["/users/me"], "upstream3")).unwrap();
 
             // when:
-            context.disable_upstream_for_all_routes(&ups_addr).unwrap();
+            let exact = context
+                .upstream_lookup(&LookupRequest::build("/users/me", "GET"))
+                .unwrap()
+                .unwrap();
+            let template = context
+                .upstream_lookup(&LookupRequest::build("/users/42", "GET"))
+                .unwrap()
+                .unwrap();
+            let regexp = context
+                .upstream_lookup(&LookupRequest::build("/users/42/orders", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            for route in context.routes.iter() {
-                for u in route.strategy.get_upstreams().iter() {
-                    if u.address == ups_addr {
-                        assert_eq!(false, u.enabled);
-                    }
-                }
-            }
+            assert_eq!("exact", exact.route_id.as_str());
+            assert_eq!("template", template.route_id.as_str());
+            assert_eq!("regexp", regexp.route_id.as_str());
         }
 
         #[test]
-        fn should_enable_upstream() {
+        fn should_prefer_more_specific_paths() {
             // given:
             let mut context = Context::build_empty();
-            context.add_route(sample_route_7_af()).unwrap();
-            context.add_route(sample_route_8_af()).unwrap();
-            let ups_addr = UpstreamAddress::FQDN(String::from("upstream21"));
+            context.add_route(sample_route("short", vec!["/static/*rest"], "upstream1")).unwrap();
+            context
+                .add_route(sample_route("long", vec!["/static/img/*rest"], "upstream2"))
+                .unwrap();
+            context.add_route(sample_route("short_re", vec!["^/api/.*$"], "upstream3")).unwrap();
+            context.add_route(sample_route("long_re", vec!["^/api/v2/.*$"], "upstream4")).unwrap();
 
             // when:
-            context.enable_upstream_for_all_routes(&ups_addr).unwrap();
+            let template = context
+                .upstream_lookup(&LookupRequest::build("/static/img/logo.png", "GET"))
+                .unwrap()
+                .unwrap();
+            let regexp = context
+                .upstream_lookup(&LookupRequest::build("/api/v2/users", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            for route in context.routes.iter() {
-                for u in route.strategy.get_upstreams().iter() {
-                    if u.address == ups_addr {
-                        assert_eq!(true, u.enabled);
-                    }
-                }
-            }
+            assert_eq!("long", template.route_id.as_str());
+            assert_eq!("long_re", regexp.route_id.as_str());
         }
 
         #[test]
-        fn should_add_route() {
+        fn should_prefer_first_added_route_when_precedence_is_equal() {
             // given:
-            let route1 = sample_route_1_af();
-            let route2 = sample_route_2_af();
             let mut context = Context::build_empty();
+            context.add_route(sample_route("first", vec!["^/a.*$"], "upstream1")).unwrap();
+            context.add_route(sample_route("second", vec!["^/.*b$"], "upstream2")).unwrap();
 
             // when:
-            let add_route_result_1 = context.add_route(route1);
-            let add_route_result_2 = context.add_route(route2);
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("/ab", "GET"))
+                .unwrap()
+                .unwrap();
 
             // then:
-            assert_eq!(true, add_route_result_1.is_ok());
-            assert_eq!(true, add_route_result_2.is_ok());
+            assert_eq!("first", upstream.route_id.as_str());
+        }
+
+        #[test]
+        fn should_prefer_route_with_higher_priority() {
+            // given:
+            let mut prioritized = sample_route("prioritized", vec!["^/users/.*$"], "upstream1");
+            prioritized.priority = 10;
+            let mut context = Context::build_empty();
+            context.add_route(sample_route("exact", vec!["/users/me"], "upstream2")).unwrap();
+            context.add_route(prioritized).unwrap();
+
+            // when:
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("/users/me", "GET"))
+                .unwrap()
+                .unwrap();
+
+            // then:
+            assert_eq!("prioritized", upstream.route_id.as_str());
+        }
+
+        #[test]
+        fn should_prefer_exact_host_over_wildcard_host_over_any_host() {
+            // given:
+            let mut exact = sample_route("exact", vec!["/users"], "upstream1");
+            exact.hosts = vec![String::from("api.example.com")];
+            let mut wildcard = sample_route("wildcard", vec!["/users"], "upstream2");
+            wildcard.hosts = vec![String::from("*.example.com")];
+            let any = sample_route("any", vec!["/users"], "upstream3");
+            let mut context = Context::build_empty();
+            context.add_route(any).unwrap();
+            context.add_route(wildcard).unwrap();
+            context.add_route(exact).unwrap();
+
+            // when:
+            let exact = context
+                .upstream_lookup(&request_with_host("API.example.com:3000", "/users", "GET"))
+                .unwrap()
+                .unwrap();
+            let wildcard = context
+                .upstream_lookup(&request_with_host("www.example.com", "/users", "GET"))
+                .unwrap()
+                .unwrap();
+            let any = context
+                .upstream_lookup(&request_with_host("example.com", "/users", "GET"))
+                .unwrap()
+                .unwrap();
+
+            // then:
+            assert_eq!("exact", exact.route_id.as_str());
+            assert_eq!("wildcard", wildcard.route_id.as_str());
+            assert_eq!("any", any.route_id.as_str());
+        }
+
+        #[test]
+        fn should_not_match_route_for_other_host() {
+            // given:
+            let mut route = sample_route("api", vec!["^/users/.*$"], "upstream1");
+            route.hosts = vec![String::from("api.example.com")];
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+
+            // when:
+            let other_host = context
+                .upstream_lookup(&request_with_host("www.example.com", "/users/1", "GET"))
+                .unwrap();
+            let no_host = context
+                .upstream_lookup(&LookupRequest::build("/users/1", "GET"))
+                .unwrap();
+
+            // then:
+            assert_eq!(None, other_host);
+            assert_eq!(None, no_host);
+        }
+
+        #[test]
+        fn should_not_add_route_with_invalid_host() {
+            // given:
+            let mut route = sample_route("api", vec!["/users"], "upstream1");
+            route.hosts = vec![String::from("api.*.com")];
+            let mut context = Context::build_empty();
+
+            // when:
+            let result = context.add_route(route);
+
+            // then:
+            assert!(matches!(result, Err(CoreError::InvalidRoutePattern(_))));
+        }
+
+        #[test]
+        fn should_track_in_flight_requests() {
+            // given:
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream1"),
+                Upstream::build_from_fqdn("upstream2"),
+            ];
+            let strategy = UpstreamStrategy::LeastConnections { upstreams, next_index: 0 };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let request = LookupRequest::build("/users", "GET");
+
+            // when:
+            let first = context.upstream_lookup(&request).unwrap().unwrap();
+            let second = context.upstream_lookup(&request).unwrap().unwrap();
+            context.complete_request("id1", &second.upstream_address, None).unwrap();
+            let third = context.upstream_lookup(&request).unwrap().unwrap();
+            context.complete_request("id1", &first.upstream_address, None).unwrap();
+            let fourth = context.upstream_lookup(&request).unwrap().unwrap();
+
+            // then:
+            assert_eq!(UpstreamAddress::FQDN(String::from("upstream1")), first.upstream_address);
+            assert_eq!(UpstreamAddress::FQDN(String::from("upstream2")), second.upstream_address);
+            assert_eq!(second.upstream_address, third.upstream_address);
+            assert_eq!(first.upstream_address, fourth.upstream_address);
+        }
+
+        #[test]
+        fn should_prefer_upstream_with_lower_latency() {
+            // given:
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream1"),
+                Upstream::build_from_fqdn("upstream2"),
+            ];
+            let strategy = UpstreamStrategy::LeastLatency {
+                upstreams,
+                decay: 0.8,
+                latencies: Vec::new(),
+            };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let request = LookupRequest::build("/users", "GET");
+            let slow = context.upstream_lookup(&request).unwrap().unwrap().upstream_address;
+            context.complete_request("id1", &slow, Some(Duration::from_millis(300))).unwrap();
+            let fast = context.upstream_lookup(&request).unwrap().unwrap().upstream_address;
+            context.complete_request("id1", &fast, Some(Duration::from_millis(20))).unwrap();
+
+            // when:
+            let result = context.upstream_lookup(&request).unwrap().unwrap();
+
+            // then:
+            assert_ne!(slow, fast);
+            assert_eq!(fast, result.upstream_address);
+        }
+
+        #[test]
+        fn should_report_failover_and_failback() {
+            // given:
+            let strategy = UpstreamStrategy::Failover {
+                tiers: vec![
+                    AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("primary")] },
+                    AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("backup")] },
+                ],
+            };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            context.add_route(sample_route("id2", vec!["/other"], "primary")).unwrap();
+            let primary = UpstreamAddress::FQDN(String::from("primary"));
+
+            // when:
+            let failovers = context.disable_upstream_for_all_routes(&primary).unwrap();
+            let repeated = context.disable_upstream_for_all_routes(&primary).unwrap();
+            let failbacks = context.enable_upstream_for_all_routes(&primary).unwrap();
+
+            // then:
+            let expected_failover = TierChange {
+                route_id: String::from("id1"),
+                from_tier: Some(0),
+                to_tier: Some(1),
+            };
+            assert_eq!(vec![expected_failover], failovers);
+            assert!(!failovers[0].is_failback());
+            assert!(repeated.is_empty());
+            assert_eq!(1, failbacks.len());
+            assert!(failbacks[0].is_failback());
+        }
+
+        #[test]
+        fn should_report_failover_on_open_circuit_and_ejection() {
+            // given:
+            let strategy = UpstreamStrategy::Failover {
+                tiers: vec![
+                    AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("primary")] },
+                    AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("backup")] },
+                ],
+            };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let primary = UpstreamAddress::FQDN(String::from("primary"));
+
+            // when:
+            let opened = context.open_circuit(&primary).unwrap();
+            let half_opened = context.half_open_circuit(&primary, 1);
+            let closed = context.close_circuit(&primary).unwrap();
+            let ejected = context.eject_upstream("id1", &primary).unwrap();
+            let restored = context.restore_upstream("id1", &primary).unwrap();
+
+            // then:
+            let expected_failover = TierChange {
+                route_id: String::from("id1"),
+                from_tier: Some(0),
+                to_tier: Some(1),
+            };
+            assert_eq!(vec![expected_failover.clone()], opened);
+            assert!(half_opened.is_ok());
+            assert_eq!(1, closed.len());
+            assert!(closed[0].is_failback());
+            assert_eq!(vec![expected_failover], ejected);
+            assert_eq!(1, restored.len());
+            assert!(restored[0].is_failback());
+        }
+
+        #[test]
+        fn should_split_traffic_to_canary() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "stable");
+            let canary = AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("canary")] };
+            route.split = Some(TrafficSplit::build(canary, 10, false));
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let request = LookupRequest::build("/users", "GET");
+            let canary_address = UpstreamAddress::FQDN(String::from("canary"));
+            let count_canary = |context: &mut Context| {
+                (0..100)
+                    .map(|_| context.upstream_lookup(&request).unwrap().unwrap().upstream_address)
+                    .filter(|address| *address == canary_address)
+                    .count()
+            };
+
+            // when:
+            let before = count_canary(&mut context);
+            context.set_traffic_split("id1", 50).unwrap();
+            let after = count_canary(&mut context);
+            context.disable_upstream_for_all_routes(&canary_address).unwrap();
+            let disabled = count_canary(&mut context);
+
+            // then:
+            assert_eq!(10, before);
+            assert_eq!(50, after);
+            assert_eq!(0, disabled);
+            assert!(matches!(
+                context.set_traffic_split("id1", 101),
+                Err(CoreError::InvalidTrafficSplit(_))
+            ));
+        }
+
+        #[test]
+        fn should_keep_clients_on_the_same_side_of_sticky_split() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "stable");
+            let canary = AlwaysFirst { upstreams: vec![Upstream::build_from_fqdn("canary")] };
+            route.split = Some(TrafficSplit::build(canary, 30, true));
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let requests: Vec<LookupRequest> = (0..1000)
+                .map(|i| {
+                    let mut request = LookupRequest::build("/users", "GET");
+                    request.client = format!("client{}", i);
+                    request
+                })
+                .collect();
+
+            // when:
+            let first: Vec<UpstreamAddress> = requests
+                .iter()
+                .map(|r| context.upstream_lookup(r).unwrap().unwrap().upstream_address)
+                .collect();
+            let second: Vec<UpstreamAddress> = requests
+                .iter()
+                .map(|r| context.upstream_lookup(r).unwrap().unwrap().upstream_address)
+                .collect();
+
+            // then:
+            assert_eq!(first, second);
+            let canary_count = first
+                .iter()
+                .filter(|a| **a == UpstreamAddress::FQDN(String::from("canary")))
+                .count();
+            assert!(canary_count > 250 && canary_count < 350, "{} canary hits", canary_count);
+        }
+
+        #[test]
+        fn should_hash_requests_by_cookie() {
+            // given:
+            let upstreams = (0..8)
+                .map(|i| Upstream::build_from_fqdn(format!("upstream{}", i).as_str()))
+                .collect();
+            let strategy = UpstreamStrategy::ConsistentHash {
+                upstreams,
+                key: HashKey::Cookie(String::from("session")),
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let requests: Vec<LookupRequest> = (0..16)
+                .map(|i| {
+                    let mut request = LookupRequest::build("/users", "GET");
+                    request.client = format!("client{}", i);
+                    request.headers = vec![(
+                        String::from("Cookie"),
+                        String::from("theme=dark; session=abc123"),
+                    )];
+                    request
+                })
+                .collect();
+
+            // when:
+            let results: Vec<UpstreamAddress> = requests
+                .iter()
+                .map(|r| context.upstream_lookup(r).unwrap().unwrap().upstream_address)
+                .collect();
+
+            // then:
+            assert!(results.iter().all(|address| *address == results[0]));
+            assert_eq!(Some("abc123"), requests[0].cookie("session"));
+            assert_eq!(None, requests[0].cookie("other"));
+        }
+
+        #[test]
+        fn should_balance_requests_without_hash_cookie() {
+            // given:
+            let upstreams = (0..4)
+                .map(|i| Upstream::build_from_fqdn(format!("upstream{}", i).as_str()))
+                .collect();
+            let strategy = UpstreamStrategy::ConsistentHash {
+                upstreams,
+                key: HashKey::Cookie(String::from("session")),
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                strategy,
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let request = LookupRequest::build("/users", "GET");
+
+            // when:
+            let results: Vec<UpstreamAddress> = (0..400)
+                .map(|_| context.upstream_lookup(&request).unwrap().unwrap().upstream_address)
+                .collect();
+
+            // then:
+            assert!(results.iter().any(|address| *address != results[0]));
+        }
+
+        #[test]
+        fn should_stick_to_upstream_named_by_cookie() {
+            // given:
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream1"),
+                Upstream::build_from_fqdn("upstream2"),
+            ];
+            let mut route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                RoundRobin { upstreams, next_index: 0 },
+            );
+            route.sticky_cookie = Some(String::from("hapi_affinity"));
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let first = context
+                .upstream_lookup(&LookupRequest::build("/users", "GET"))
+                .unwrap()
+                .unwrap();
+            let (name, token) = first.affinity_cookie.clone().unwrap();
+            let mut sticky_request = LookupRequest::build("/users", "GET");
+            sticky_request.headers = vec![(String::from("cookie"), format!("{}={}", name, token))];
+
+            // when:
+            let second = context.upstream_lookup(&sticky_request).unwrap().unwrap();
+            let third = context.upstream_lookup(&sticky_request).unwrap().unwrap();
+            context.disable_upstream_for_all_routes(&first.upstream_address).unwrap();
+            let fallback = context.upstream_lookup(&sticky_request).unwrap().unwrap();
+
+            // then:
+            assert_eq!("hapi_affinity", name.as_str());
+            assert_eq!(first.upstream_address, second.upstream_address);
+            assert_eq!(first.upstream_address, third.upstream_address);
+            assert_eq!(None, second.affinity_cookie);
+            assert_ne!(first.upstream_address, fallback.upstream_address);
+            assert_eq!(
+                Some(fallback.upstream_address.affinity_token()),
+                fallback.affinity_cookie.map(|(_, token)| token)
+            );
+        }
+
+        #[test]
+        fn should_not_return_excluded_upstreams() {
+            // given:
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream1"),
+                Upstream::build_from_fqdn("upstream2"),
+            ];
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                AlwaysFirst { upstreams },
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let mut request = LookupRequest::build("/users", "GET");
+            request.excluded_upstreams = vec![UpstreamAddress::FQDN(String::from("upstream1"))];
+            let mut all_excluded = request.clone();
+            all_excluded
+                .excluded_upstreams
+                .push(UpstreamAddress::FQDN(String::from("upstream2")));
+
+            // when:
+            let alternate = context.upstream_lookup(&request).unwrap().unwrap();
+            let none_left = context.upstream_lookup(&all_excluded);
+            let first = context
+                .upstream_lookup(&LookupRequest::build("/users", "GET"))
+                .unwrap()
+                .unwrap();
+
+            // then:
+            assert_eq!(
+                UpstreamAddress::FQDN(String::from("upstream2")),
+                alternate.upstream_address
+            );
+            assert!(matches!(none_left, Err(CoreError::NoHealthyUpstream(id)) if id == "id1"));
+            assert_eq!(
+                UpstreamAddress::FQDN(String::from("upstream1")),
+                first.upstream_address
+            );
+        }
+
+        #[test]
+        fn should_not_return_upstreams_with_open_circuit() {
+            // given:
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream1"),
+                Upstream::build_from_fqdn("upstream2"),
+            ];
+            let route = Route::build(
+                String::from("id1"),
+                String::from("route1"),
+                vec![String::from("GET")],
+                vec![String::from("/users")],
+                AlwaysFirst { upstreams },
+            );
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            let upstream1 = UpstreamAddress::FQDN(String::from("upstream1"));
+            let upstream2 = UpstreamAddress::FQDN(String::from("upstream2"));
+            let request = LookupRequest::build("/users", "GET");
+
+            // when:
+            context.open_circuit(&upstream1).unwrap();
+            let open = context.upstream_lookup(&request).unwrap().unwrap();
+            context.half_open_circuit(&upstream1, 1).unwrap();
+            let trial = context.upstream_lookup(&request).unwrap().unwrap();
+            let after_trial = context.upstream_lookup(&request).unwrap().unwrap();
+            context.close_circuit(&upstream1).unwrap();
+            let closed = context.upstream_lookup(&request).unwrap().unwrap();
+
+            // then:
+            assert_eq!(upstream2, open.upstream_address);
+            assert_eq!(upstream1, trial.upstream_address);
+            assert_eq!(upstream2, after_trial.upstream_address);
+            assert_eq!(upstream1, closed.upstream_address);
+            assert!(context.get_all_upstreams().unwrap().iter().all(|u| u.enabled));
+        }
+
+        #[test]
+        fn should_not_return_upstreams_ejected_from_matched_route() {
+            // given:
+            let mut context = Context::build_empty();
+            for (id, path) in [("id1", "/users"), ("id2", "/orders")] {
+                let upstreams = vec![
+                    Upstream::build_from_fqdn("upstream1"),
+                    Upstream::build_from_fqdn("upstream2"),
+                ];
+                let route = Route::build(
+                    String::from(id),
+                    String::from(id),
+                    vec![String::from("GET")],
+                    vec![String::from(path)],
+                    AlwaysFirst { upstreams },
+                );
+                context.add_route(route).unwrap();
+            }
+            let upstream1 = UpstreamAddress::FQDN(String::from("upstream1"));
+            let upstream2 = UpstreamAddress::FQDN(String::from("upstream2"));
+            let users = LookupRequest::build("/users", "GET");
+            let orders = LookupRequest::build("/orders", "GET");
+
+            // when:
+            context.eject_upstream("id1", &upstream1).unwrap();
+            let ejected = context.upstream_lookup(&users).unwrap().unwrap();
+            let other_route = context.upstream_lookup(&orders).unwrap().unwrap();
+            context.restore_upstream("id1", &upstream1).unwrap();
+            let restored = context.upstream_lookup(&users).unwrap().unwrap();
+
+            // then:
+            assert_eq!(upstream2, ejected.upstream_address);
+            assert_eq!(upstream1, other_route.upstream_address);
+            assert_eq!(upstream1, restored.upstream_address);
+            assert!(context.eject_upstream("id3", &upstream1).is_err());
+            let unknown = UpstreamAddress::FQDN(String::from("upstream3"));
+            assert!(matches!(
+                context.eject_upstream("id1", &unknown),
+                Err(CoreError::UpstreamNotExists)
+            ));
+        }
+
+        #[test]
+        fn should_forget_ejections_of_removed_route() {
+            // given:
+            let mut context = Context::build_empty();
+            let route = || {
+                let upstreams = vec![
+                    Upstream::build_from_fqdn("upstream1"),
+                    Upstream::build_from_fqdn("upstream2"),
+                ];
+                Route::build(
+                    String::from("id1"),
+                    String::from("route1"),
+                    vec![String::from("GET")],
+                    vec![String::from("/users")],
+                    AlwaysFirst { upstreams },
+                )
+            };
+            context.add_route(route()).unwrap();
+            let upstream1 = UpstreamAddress::FQDN(String::from("upstream1"));
+            context.eject_upstream("id1", &upstream1).unwrap();
+
+            // when:
+            context.remove_route("id1").unwrap();
+            context.add_route(route()).unwrap();
+            let result = context.upstream_lookup(&LookupRequest::build("/users", "GET"));
+
+            // then:
+            assert_eq!(upstream1, result.unwrap().unwrap().upstream_address);
+        }
+
+        #[test]
+        fn should_return_mirror_of_matched_route() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "upstream1");
+            route.mirror = Some(UpstreamAddress::FQDN(String::from("shadow")));
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+            context.add_route(sample_route("id2", vec!["/orders"], "upstream2")).unwrap();
+
+            // when:
+            let mirrored = context.upstream_lookup(&LookupRequest::build("/users", "GET"));
+            let plain = context.upstream_lookup(&LookupRequest::build("/orders", "GET"));
+
+            // then:
+            assert_eq!(
+                Some(UpstreamAddress::FQDN(String::from("shadow"))),
+                mirrored.unwrap().unwrap().mirror
+            );
+            assert_eq!(None, plain.unwrap().unwrap().mirror);
+        }
+
+        #[test]
+        fn should_rewrite_upstream_path() {
+            // given:
+            let mut stripped = sample_route("id1", vec!["/svc-a/*rest"], "upstream1");
+            stripped.rewrites = vec![RewriteRule::StripPrefix(String::from("/svc-a/"))];
+            let mut prefixed = sample_route("id2", vec!["/users/*rest"], "upstream2");
+            prefixed.rewrites = vec![RewriteRule::AddPrefix(String::from("/api/v2"))];
+            let mut replaced = sample_route("id3", vec!["/orders/{id}"], "upstream3");
+            replaced.rewrites = vec![
+                RewriteRule::Replace {
+                    pattern: String::from("^/orders/(?P<id>[^/]+)$"),
+                    replacement: String::from("/purchases/${id}/detail"),
+                },
+                RewriteRule::AddPrefix(String::from("/legacy")),
+            ];
+            let mut context = Context::build_empty();
+            context.add_route(stripped).unwrap();
+            context.add_route(prefixed).unwrap();
+            context.add_route(replaced).unwrap();
+            context.add_route(sample_route("id4", vec!["/svc-ab"], "upstream4")).unwrap();
+            let mut lookup = |path: &str| {
+                let request = LookupRequest::build(path, "GET");
+                context.upstream_lookup(&request).unwrap().unwrap().upstream_path
+            };
+
+            // when:
+            let results = vec![
+                lookup("/svc-a/users/1"),
+                lookup("/svc-a"),
+                lookup("/users/1"),
+                lookup("/orders/42"),
+                lookup("/svc-ab"),
+            ];
+
+            // then:
+            assert_eq!(
+                vec![
+                    "/users/1",
+                    "/",
+                    "/api/v2/users/1",
+                    "/legacy/purchases/42/detail",
+                    "/svc-ab"
+                ],
+                results
+            );
+        }
+
+        #[test]
+        fn should_keep_leading_slash_of_replaced_path() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "upstream1");
+            route.rewrites = vec![RewriteRule::Replace {
+                pattern: String::from("^/"),
+                replacement: String::new(),
+            }];
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+
+            // when:
+            let result = context
+                .upstream_lookup(&LookupRequest::build("/users", "GET"))
+                .unwrap()
+                .unwrap();
+
+            // then:
+            assert_eq!("/users", result.upstream_path);
+        }
+
+        #[test]
+        fn should_not_add_route_with_relative_prefix() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "upstream1");
+            route.rewrites = vec![RewriteRule::AddPrefix(String::from("v2"))];
+            let mut context = Context::build_empty();
+
+            // when:
+            let result = context.add_route(route);
+
+            // then:
+            assert!(matches!(result, Err(CoreError::InvalidRoutePattern(_))));
+            assert!(context.get_all_routes().unwrap().is_empty());
+        }
+
+        #[test]
+        fn should_not_add_route_with_invalid_rewrite() {
+            // given:
+            let mut route = sample_route("id1", vec!["/users"], "upstream1");
+            route.rewrites = vec![RewriteRule::Replace {
+                pattern: String::from("^/users/(["),
+                replacement: String::from("/"),
+            }];
+            let mut context = Context::build_empty();
+
+            // when:
+            let result = context.add_route(route);
+
+            // then:
+            assert!(matches!(result, Err(CoreError::InvalidRoutePattern(_))));
+            assert!(context.get_all_routes().unwrap().is_empty());
+        }
+
+        #[test]
+        fn should_match_route_by_header_predicate() {
+            // given:
+            let mut v2 = sample_route("v2", vec!["/users"], "upstream2");
+            v2.predicates = vec![RequestPredicate {
+                source: PredicateSource::Header(String::from("X-Api-Version")),
+                condition: PredicateCondition::Equals(String::from("2")),
+            }];
+            let mut context = Context::build_empty();
+            context.add_route(sample_route("default", vec!["/users"], "upstream1")).unwrap();
+            context.add_route(v2).unwrap();
+            let mut request = LookupRequest::build("/users", "GET");
+            request.headers = vec![(String::from("x-api-version"), String::from("2"))];
+
+            // when:
+            let versioned = context.upstream_lookup(&request).unwrap().unwrap();
+            let default = context
+                .upstream_lookup(&LookupRequest::build("/users", "GET"))
+                .unwrap()
+                .unwrap();
+
+            // then:
+            assert_eq!("v2", versioned.route_id.as_str());
+            assert_eq!("default", default.route_id.as_str());
+        }
+
+        #[test]
+        fn should_match_route_by_query_predicates() {
+            // given:
+            let mut beta = sample_route("beta", vec!["^/users/.*$"], "upstream2");
+            beta.predicates = vec![
+                RequestPredicate {
+                    source: PredicateSource::Query(String::from("beta")),
+                    condition: PredicateCondition::Matches(String::from("true|yes")),
+                },
+                RequestPredicate {
+                    source: PredicateSource::Query(String::from("legacy")),
+                    condition: PredicateCondition::Absent,
+                },
+            ];
+            let mut context = Context::build_empty();
+            context.add_route(beta).unwrap();
+            let mut matching = LookupRequest::build("/users/1", "GET");
+            matching.query = vec![(String::from("beta"), String::from("yes"))];
+            let mut not_matching = matching.clone();
+            not_matching.query.push((String::from("legacy"), String::new()));
+
+            // when:
+            let found = context.upstream_lookup(&matching).unwrap();
+            let not_found = context.upstream_lookup(&not_matching).unwrap();
+
+            // then:
+            assert_eq!("beta", found.unwrap().route_id.as_str());
+            assert_eq!(None, not_found);
+        }
+
+        #[test]
+        fn should_not_add_route_with_invalid_predicate() {
+            // given:
+            let mut route = sample_route("invalid", vec!["/users"], "upstream1");
+            route.predicates = vec![RequestPredicate {
+                source: PredicateSource::Header(String::from("X-Api-Version")),
+                condition: PredicateCondition::Matches(String::from("(2")),
+            }];
+            let mut context = Context::build_empty();
+
+            // when:
+            let result = context.add_route(route);
+
+            // then:
+            assert!(matches!(result, Err(CoreError::InvalidRoutePattern(_))));
+        }
+
+        #[test]
+        fn should_find_shadowing_routes() {
+            // given:
+            let mut context = Context::build_empty();
+            context.add_route(sample_route("users", vec!["/users/{id}"], "upstream1")).unwrap();
+            context.add_route(sample_route("catch_all", vec!["^/.*$"], "upstream2")).unwrap();
+            context.add_route(sample_route("shadowed", vec!["^/.*$"], "upstream3")).unwrap();
+
+            // when:
+            let shadowed_by = context.find_shadowing_routes("shadowed").unwrap();
+
+            // then:
+            assert_eq!(vec![String::from("catch_all")], shadowed_by);
+        }
+
+        #[test]
+        fn should_not_report_partially_shadowed_route() {
+            // given:
+            let mut context = Context::build_empty();
+            context.add_route(sample_route("users", vec!["/users/{id}"], "upstream1")).unwrap();
+            context
+                .add_route(sample_route("other", vec!["/users/42", "/orders"], "upstream2"))
+                .unwrap();
+
+            // when:
+            let shadowed_by = context.find_shadowing_routes("other").unwrap();
+
+            // then:
+            assert!(shadowed_by.is_empty());
+        }
+
+        #[test]
+        fn should_not_find_route_for_non_exact_match() {
+            // given:
+            let mut context = Context::build_empty();
+            context.add_route(sample_route_5_af()).unwrap();
+
+            // when:
+            let upstream = context.upstream_lookup(&LookupRequest::build("uri5", "GET")).unwrap();
+
+            // then:
+            assert_eq!(upstream, None)
+        }
+
+        #[test]
+        fn should_find_no_healthy_upstream_if_all_upstreams_are_disabled() {
+            // given:
+            let mut route = sample_route_1_rr();
+            let addresses: Vec<UpstreamAddress> = route.strategy.get_upstreams().iter().map(|u| u.address.clone()).collect();
+            for a in addresses.iter() {
+                route.strategy.disable_upstream(a);
+            }
+            let mut context = Context::build_empty();
+            context.add_route(route).unwrap();
+
+            // when:
+            let upstream = context.upstream_lookup(&LookupRequest::build("uri1", "GET"));
+
+            // then:
+            assert!(matches!(upstream, Err(CoreError::NoHealthyUpstream(_))))
+        }
+
+        #[test]
+        fn should_disable_upstream() {
+            // given:
+            let mut context = Context::build_empty();
+            context.add_route(sample_route_5_af()).unwrap();
+            context.add_route(sample_route_6_af()).unwrap();
+            let ups_addr = UpstreamAddress::FQDN(String::from("upstream21"));
+
+            // when:
+            context.disable_upstream_for_all_routes(&ups_addr).unwrap();
+
+            // then:
+            for route in context.routes.iter() {
+                for u in route.strategy.get_upstreams().iter() {
+                    if u.address == ups_addr {
+                        assert_eq!(false, u.enabled);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn should_enable_upstream() {
+            // given:
+            let mut context = Context::build_empty();
+            context.add_route(sample_route_7_af()).unwrap();
+            context.add_route(sample_route_8_af()).unwrap();
+            let ups_addr = UpstreamAddress::FQDN(String::from("upstream21"));
+
+            // when:
+            context.enable_upstream_for_all_routes(&ups_addr).unwrap();
+
+            // then:
+            for route in context.routes.iter() {
+                for u in route.strategy.get_upstreams().iter() {
+                    if u.address == ups_addr {
+                        assert_eq!(true, u.enabled);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn should_add_route() {
+            // given:
+            let route1 = sample_route_1_af();
+            let route2 = sample_route_2_af();
+            let mut context = Context::build_empty();
+
+            // when:
+            let add_route_result_1 = context.add_route(route1);
+            let add_route_result_2 = context.add_route(route2);
+
+            // then:
+            assert_eq!(true, add_route_result_1.is_ok());
+            assert_eq!(true, add_route_result_2.is_ok());
             assert_eq!(2, context.routes.len());
             assert_eq!(3, context.routing_table.len());
             assert_eq!(2, context.route_index.len());
         }
 
         #[test]
-        fn should_not_add_route_if_it_exists() {
+        fn should_not_add_route_if_it_exists() {
+            // given:
+            let route1 = sample_route_1_af();
+            let route2 = route1.clone();
+            let mut context = Context::build_empty();
+            context.add_route(route1).unwrap();
+
+            // when:
+            let add_result = context.add_route(route2);
+
+            // then:
+            assert_eq!(true, add_result.is_err());
+            assert_eq!(1, context.routes.len());
+            assert_eq!(2, context.routing_table.len());
+            assert_eq!(1, context.route_index.len());
+        }
+
+        #[test]
+        fn should_not_add_route_with_invalid_pattern() {
+            // given:
+            let invalid_path = sample_route("invalid_path", vec!["^/users/(.*$"], "upstream1");
+            let mut invalid_method = sample_route("invalid_method", vec!["/users"], "upstream1");
+            invalid_method.methods = vec![String::from("[GET")];
+            let mut context = Context::build_empty();
+
+            // when:
+            let path_result = context.add_route(invalid_path);
+            let method_result = context.add_route(invalid_method);
+
+            // then:
+            assert!(matches!(path_result, Err(CoreError::InvalidRoutePattern(_))));
+            assert!(matches!(method_result, Err(CoreError::InvalidRoutePattern(_))));
+            assert_eq!(0, context.routes.len());
+            assert_eq!(0, context.matchers.len());
+        }
+
+        #[test]
+        fn should_match_regexp_routes_after_removing_a_route() {
             // given:
-            let route1 = sample_route_1_af();
-            let route2 = route1.clone();
             let mut context = Context::build_empty();
-            context.add_route(route1).unwrap();
+            context.add_route(sample_route("first", vec!["^/a/.*$"], "upstream1")).unwrap();
+            context.add_route(sample_route("second", vec!["^/b/.*$"], "upstream2")).unwrap();
+            context.upstream_lookup(&LookupRequest::build("/b/1", "GET")).unwrap().unwrap();
 
             // when:
-            let add_result = context.add_route(route2);
+            context.remove_route("first").unwrap();
+            let upstream = context
+                .upstream_lookup(&LookupRequest::build("/b/1", "GET"))
+                .unwrap()
+                .unwrap();
+            let missing = context.upstream_lookup(&LookupRequest::build("/a/1", "GET")).unwrap();
 
             // then:
-            assert_eq!(true, add_result.is_err());
-            assert_eq!(1, context.routes.len());
-            assert_eq!(2, context.routing_table.len());
-            assert_eq!(1, context.route_index.len());
+            assert_eq!("second", upstream.route_id.as_str());
+            assert_eq!(None, missing);
         }
 
         #[test]
@@ -423,6 +2184,24 @@ pub(crate) mod context {
             assert_eq!(0, context.routing_table.len());
         }
 
+        fn request_with_host(host: &str, path: &str, method: &str) -> LookupRequest {
+            let mut request = LookupRequest::build(path, method);
+            request.host = Some(String::from(host));
+            request
+        }
+
+        fn sample_route(id: &str, paths: Vec<&str>, upstream: &str) -> Route {
+            let upstreams = vec![Upstream::build_from_fqdn(upstream)];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from(id),
+                String::from(id),
+                vec![String::from("GET")],
+                paths.iter().map(|p| p.to_string()).collect(),
+                strategy,
+            )
+        }
+
         fn sample_route_1_af() -> Route {
             let upstreams = vec![
                 Upstream::build_from_fqdn("upstream1"),
@@ -468,140 +2247,649 @@ pub(crate) mod context {
             )
         }
 
-        fn sample_route_2_rr() -> Route {
-            let upstreams = vec![
-                Upstream::build_from_fqdn("upstream3"),
-                Upstream::build_from_fqdn("upstream4"),
-            ];
-            let strategy = RoundRobin { upstreams, next_index: 0 };
-            Route::build(
-                String::from("id2"),
-                String::from("route2"),
-                vec![String::from("GET")],
-                vec![String::from("uri2"), String::from("uri3")],
-                strategy,
-            )
+        fn sample_route_2_rr() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream3"),
+                Upstream::build_from_fqdn("upstream4"),
+            ];
+            let strategy = RoundRobin { upstreams, next_index: 0 };
+            Route::build(
+                String::from("id2"),
+                String::from("route2"),
+                vec![String::from("GET")],
+                vec![String::from("uri2"), String::from("uri3")],
+                strategy,
+            )
+        }
+
+        fn sample_route_3_af() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream20"),
+                Upstream::build_from_fqdn("upstream21"),
+            ];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id3"),
+                String::from("route3"),
+                vec![String::from("GET")],
+                vec![String::from("^uri.*$")],
+                strategy,
+            )
+        }
+
+        fn sample_route_4_af() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream10"),
+                Upstream::build_from_fqdn("upstream11"),
+            ];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id4"),
+                String::from("route4"),
+                vec![String::from("^.+$")],
+                vec![String::from("uri4")],
+                strategy,
+            )
+        }
+
+        fn sample_route_5_af() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream20"),
+                Upstream::build_from_fqdn("upstream21"),
+            ];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id5"),
+                String::from("route5"),
+                vec![String::from("GET")],
+                vec![String::from("uri")],
+                strategy,
+            )
+        }
+
+        fn sample_route_6_af() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream21"),
+                Upstream::build_from_fqdn("upstream22"),
+            ];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id6"),
+                String::from("route6"),
+                vec![String::from("GET")],
+                vec![String::from("uri2")],
+                strategy,
+            )
+        }
+
+        fn sample_route_7_af() -> Route {
+            let upstream1 = Upstream::build_from_fqdn("upstream20");
+            let mut upstream2 = Upstream::build_from_fqdn("upstream21");
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1, upstream2];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id7"),
+                String::from("route7"),
+                vec![String::from("GET")],
+                vec![String::from("uri")],
+                strategy,
+            )
+        }
+
+        fn sample_route_8_af() -> Route {
+            let mut upstream1 = Upstream::build_from_fqdn("upstream21");
+            upstream1.enabled = false;
+            let upstream2 = Upstream::build_from_fqdn("upstream22");
+            let upstreams = vec![upstream1, upstream2];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id8"),
+                String::from("route8"),
+                vec![String::from("GET")],
+                vec![String::from("uri2")],
+                strategy,
+            )
+        }
+
+        fn sample_route_9_af() -> Route {
+            let upstreams = vec![
+                Upstream::build_from_fqdn("upstream30"),
+                Upstream::build_from_fqdn("upstream31"),
+            ];
+            let strategy = AlwaysFirst { upstreams };
+            Route::build(
+                String::from("id9"),
+                String::from("route9"),
+                vec![String::from("GET")],
+                vec![String::from("/users/{id}"), String::from("/static/*rest")],
+                strategy,
+            )
+        }
+    }
+}
+
+pub(crate) mod route {
+    use crate::modules::core::upstream::{hash_of, Upstream, UpstreamAddress, UpstreamStrategy};
+    use std::cmp::Reverse;
+    use std::time::Duration;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct Route {
+        pub id: String,
+        pub name: String,
+        pub methods: Vec<String>,
+        pub paths: Vec<String>,
+        pub strategy: UpstreamStrategy,
+        pub priority: i32, // routes with higher priority are matched first
+        pub hosts: Vec<String>, // exact or wildcard (`*.example.com`) hosts, empty for any host
+        pub predicates: Vec<RequestPredicate>, // all of them must hold for the route to match
+        pub sticky_cookie: Option<String>, // name of the affinity cookie, if sessions are sticky
+        pub split: Option<TrafficSplit>, // canary upstreams getting part of the traffic, if any
+        pub mirror: Option<UpstreamAddress>, // shadow upstream whose responses are discarded
+        pub rewrites: Vec<RewriteRule>, // applied in order to the path forwarded to the upstream
+        pub timeouts: UpstreamTimeouts,
+        pub retry: Option<RetryPolicy>, // requests are not retried without a policy
+    }
+
+    impl Route {
+        pub fn build(
+            id: String,
+            name: String,
+            methods: Vec<String>,
+            paths: Vec<String>,
+            strategy: UpstreamStrategy,
+        ) -> Self {
+            Route {
+                id,
+                name,
+                methods,
+                paths,
+                strategy,
+                priority: 0,
+                hosts: Vec::new(),
+                predicates: Vec::new(),
+                sticky_cookie: None,
+                split: None,
+                mirror: None,
+                rewrites: Vec::new(),
+                timeouts: UpstreamTimeouts::default(),
+                retry: None,
+            }
+        }
+
+        /// Returns the upstreams of this route, including the canary ones
+        pub fn upstreams(&self) -> Vec<&Upstream> {
+            let mut upstreams = self.strategy.get_upstreams();
+            if let Some(split) = &self.split {
+                upstreams.extend(split.canary.get_upstreams());
+            }
+            upstreams
+        }
+
+        /// Returns the strategy of this route along with the canary one, if any
+        pub fn strategies_mut(&mut self) -> impl Iterator<Item = &mut UpstreamStrategy> {
+            let canary = self.split.as_mut().map(|split| &mut split.canary);
+            std::iter::once(&mut self.strategy).chain(canary)
+        }
+    }
+
+    /// Canary set of upstreams getting a percentage of the traffic of a route
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct TrafficSplit {
+        pub canary: UpstreamStrategy,
+        pub percentage: u8, // share of the requests sent to the canary upstreams, 0 to 100
+        pub sticky: bool, // whether each client keeps going to the same side of the split
+        pub credit: u8, // accumulates percentage points, a request goes to the canary at 100
+    }
+
+    impl TrafficSplit {
+        pub fn build(canary: UpstreamStrategy, percentage: u8, sticky: bool) -> Self {
+            TrafficSplit {
+                canary,
+                percentage: percentage.min(100),
+                sticky,
+                credit: 0,
+            }
+        }
+
+        /// Whether the next request of the given client goes to the canary upstreams. Sticky
+        /// splits hash the client, the rest send exactly `percentage` out of every 100 requests
+        pub fn picks_canary(&mut self, client: &str) -> bool {
+            if self.sticky {
+                hash_of(client) % 100 < self.percentage as u64
+            } else {
+                self.credit += self.percentage;
+                if self.credit >= 100 {
+                    self.credit -= 100;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    /// Deadlines of the requests forwarded to the upstreams of a route, counted from the moment
+    /// the request is sent. Unset deadlines fall back to the defaults in the settings
+    #[derive(Clone, Debug, Default, PartialEq)]
+    pub(crate) struct UpstreamTimeouts {
+        pub connect: Option<Duration>,    // to open a connection to the upstream
+        pub first_byte: Option<Duration>, // to receive the response head
+        pub total: Option<Duration>,      // to receive the whole response
+    }
+
+    /// Retries of the requests whose upstream fails, each of them on a different upstream
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct RetryPolicy {
+        pub max_attempts: u32, // including the first one
+        pub retry_on: Vec<RetryOn>,
+        pub idempotent_only: bool, // only retry requests with idempotent methods
+        pub backoff: Duration,     // before the first retry, doubled before each of the next ones
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum RetryOn {
+        ConnectError,
+        BadGateway,         // 502
+        ServiceUnavailable, // 503
+        GatewayTimeout,     // 504, also returned when the upstream times out
+    }
+
+    const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+    impl RetryPolicy {
+        pub fn build(max_attempts: u32) -> Self {
+            RetryPolicy {
+                max_attempts,
+                retry_on: vec![
+                    RetryOn::ConnectError,
+                    RetryOn::BadGateway,
+                    RetryOn::ServiceUnavailable,
+                    RetryOn::GatewayTimeout,
+                ],
+                idempotent_only: true,
+                backoff: Duration::from_millis(25),
+            }
+        }
+
+        /// Returns `true` if requests with the given method may be retried
+        pub fn allows(&self, method: &str) -> bool {
+            !self.idempotent_only || IDEMPOTENT_METHODS.contains(&method)
+        }
+
+        /// Returns `true` if upstream responses with the given status are retried
+        pub fn retries_status(&self, status: u16) -> bool {
+            let retry_on = match status {
+                502 => RetryOn::BadGateway,
+                503 => RetryOn::ServiceUnavailable,
+                504 => RetryOn::GatewayTimeout,
+                _ => return false,
+            };
+            self.retry_on.contains(&retry_on)
+        }
+
+        /// Time to wait before the given retry (1 for the first one)
+        pub fn backoff_before(&self, retry: u32) -> Duration {
+            self.backoff * 2u32.saturating_pow(retry.saturating_sub(1))
+        }
+    }
+
+    /// Rule rewriting the request path before it is forwarded to the upstream
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum RewriteRule {
+        StripPrefix(String),
+        AddPrefix(String),
+        Replace {
+            pattern: String,     // regular expression, only its first match is replaced
+            replacement: String, // may refer to capture groups as `$1` or `${name}`
+        },
+    }
+
+    /// Condition on a request header or query parameter that must hold for a route to match
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct RequestPredicate {
+        pub source: PredicateSource,
+        pub condition: PredicateCondition,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum PredicateSource {
+        Header(String), // header names are matched case-insensitively
+        Query(String),
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum PredicateCondition {
+        Equals(String),
+        Matches(String), // regular expression that must match the whole value
+        Present,
+        Absent,
+    }
+
+    /// Host a route applies to, parsed from the route hosts
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum HostPattern {
+        Any,
+        Exact(String),
+        Wildcard(String), // suffix including the leading dot, e.g. `.example.com`
+    }
+
+    impl HostPattern {
+        /// Parses an exact host (`api.example.com`) or a wildcard host (`*.example.com`), which
+        /// matches any subdomain but not the domain itself. `*` matches any host.
+        /// Returns `None` if the host is not well formed
+        pub fn parse(host: &str) -> Option<Self> {
+            let host = host.trim().to_ascii_lowercase();
+            if host == "*" {
+                Some(HostPattern::Any)
+            } else if let Some(suffix) = host.strip_prefix('*') {
+                let valid = suffix.starts_with('.') && suffix.len() > 1 && !suffix.contains('*');
+                valid.then(|| HostPattern::Wildcard(suffix.to_string()))
+            } else if host.is_empty() || host.contains('*') {
+                None
+            } else {
+                Some(HostPattern::Exact(host))
+            }
+        }
+
+        /// Lowercases the given host (or authority) and strips its port, if any
+        pub fn normalize(host: &str) -> String {
+            let host = host.trim();
+            let without_port = if host.starts_with('[') {
+                // IPv6 literal, such as `[::1]:3000`
+                host.split_inclusive(']').next().unwrap_or(host)
+            } else {
+                host.split(':').next().unwrap_or(host)
+            };
+            without_port.to_ascii_lowercase()
+        }
+
+        /// Returns `true` if the given normalized host matches this pattern. Requests without
+        /// host only match routes that apply to any host
+        pub fn matches(&self, host: Option<&str>) -> bool {
+            match (self, host) {
+                (HostPattern::Any, _) => true,
+                (HostPattern::Exact(exact), Some(host)) => exact == host,
+                (HostPattern::Wildcard(suffix), Some(host)) => {
+                    host.len() > suffix.len() && host.ends_with(suffix.as_str())
+                }
+                (_, None) => false,
+            }
+        }
+
+        /// Returns `true` if every host matched by the other pattern is also matched by this one
+        pub fn covers(&self, other: &HostPattern) -> bool {
+            match (self, other) {
+                (HostPattern::Any, _) => true,
+                (HostPattern::Wildcard(_), HostPattern::Exact(host)) => self.matches(Some(host)),
+                (HostPattern::Wildcard(suffix), HostPattern::Wildcard(other_suffix)) => {
+                    other_suffix.ends_with(suffix.as_str())
+                }
+                _ => self == other,
+            }
+        }
+
+        /// Exact hosts first, then wildcard hosts (longer suffixes first), then any host
+        pub fn rank(&self) -> (u8, Reverse<usize>) {
+            match self {
+                HostPattern::Exact(_) => (0, Reverse(0)),
+                HostPattern::Wildcard(suffix) => (1, Reverse(suffix.len())),
+                HostPattern::Any => (2, Reverse(0)),
+            }
+        }
+    }
+
+    /// Parameters captured from a request path by a path template, in template order
+    pub(crate) type PathParams = Vec<(String, String)>;
+
+    /// Route path template, such as `/users/{id}` or `/static/*rest`.
+    /// A `{name}` segment captures exactly one non-empty path segment, and a trailing `*name`
+    /// segment captures the rest of the path, which turns the template into a prefix route. The
+    /// rest segment may be left unnamed (`/static/*`) to match the prefix without capturing.
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) struct PathTemplate {
+        segments: Vec<Segment>,
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Segment {
+        Literal(String),
+        Param(String),
+        Rest(Option<String>),
+    }
+
+    impl PathTemplate {
+        /// Parses the given route path as a template.
+        /// Returns `None` if the path is not a template, either because it has no parameter
+        /// segments or because it is not well formed (it is then treated as a regular expression)
+        pub fn parse(path: &str) -> Option<Self> {
+            let raw_segments: Vec<&str> = path.strip_prefix('/')?.split('/').collect();
+            let mut segments = Vec::new();
+
+            for (position, raw) in raw_segments.iter().enumerate() {
+                let segment = if let Some(name) = raw.strip_prefix('{') {
+                    let name = name.strip_suffix('}')?;
+                    if !is_param_name(name) {
+                        return None;
+                    }
+                    Segment::Param(name.to_string())
+                } else if let Some(name) = raw.strip_prefix('*') {
+                    if position != raw_segments.len() - 1 {
+                        return None;
+                    }
+                    if name.is_empty() {
+                        Segment::Rest(None)
+                    } else if is_param_name(name) {
+                        Segment::Rest(Some(name.to_string()))
+                    } else {
+                        return None;
+                    }
+                } else if raw.contains(['{', '}', '*']) {
+                    return None;
+                } else {
+                    Segment::Literal(raw.to_string())
+                };
+                segments.push(segment);
+            }
+
+            let has_params = segments
+                .iter()
+                .any(|s| !matches!(s, Segment::Literal(_)));
+            has_params.then_some(PathTemplate { segments })
+        }
+
+        /// Returns (literal segments, non-prefix segments): the higher, the more specific
+        pub fn specificity(&self) -> (usize, usize) {
+            let literals = self
+                .segments
+                .iter()
+                .filter(|s| matches!(s, Segment::Literal(_)))
+                .count();
+            let fixed = self
+                .segments
+                .iter()
+                .filter(|s| !matches!(s, Segment::Rest(_)))
+                .count();
+            (literals, fixed)
+        }
+
+        /// Matches the given request path against this template, returning the captured
+        /// parameters if it matches
+        pub fn captures(&self, path: &str) -> Option<PathParams> {
+            let mut remaining = Some(path.strip_prefix('/')?);
+            let mut params = PathParams::new();
+
+            for segment in self.segments.iter() {
+                if let Segment::Rest(name) = segment {
+                    if let Some(name) = name {
+                        let rest = remaining.unwrap_or_default().to_string();
+                        params.push((name.clone(), rest));
+                    }
+                    return Some(params);
+                }
+
+                let current = remaining?;
+                let (value, rest) = match current.split_once('/') {
+                    Some((value, rest)) => (value, Some(rest)),
+                    None => (current, None),
+                };
+                match segment {
+                    Segment::Literal(literal) if literal == value => {}
+                    Segment::Param(name) if !value.is_empty() => {
+                        params.push((name.clone(), value.to_string()));
+                    }
+                    _ => return None,
+                }
+                remaining = rest;
+            }
+
+            remaining.is_none().then_some(params)
+        }
+    }
+
+    fn is_param_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(first) => {
+                (first.is_ascii_alphabetic() || first == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            None => false,
         }
+    }
 
-        fn sample_route_3_af() -> Route {
-            let upstreams = vec![
-                Upstream::build_from_fqdn("upstream20"),
-                Upstream::build_from_fqdn("upstream21"),
-            ];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id3"),
-                String::from("route3"),
-                vec![String::from("GET")],
-                vec![String::from("^uri.*$")],
-                strategy,
-            )
+    #[cfg(test)]
+    mod tests {
+        use crate::modules::core::route::{HostPattern, PathTemplate};
+
+        #[test]
+        fn should_parse_host_patterns() {
+            assert_eq!(Some(HostPattern::Any), HostPattern::parse("*"));
+            assert_eq!(
+                Some(HostPattern::Exact(String::from("api.example.com"))),
+                HostPattern::parse("API.example.com")
+            );
+            assert_eq!(
+                Some(HostPattern::Wildcard(String::from(".example.com"))),
+                HostPattern::parse("*.example.com")
+            );
+            assert_eq!(None, HostPattern::parse("*example.com"));
+            assert_eq!(None, HostPattern::parse("api.*.com"));
         }
 
-        fn sample_route_4_af() -> Route {
-            let upstreams = vec![
-                Upstream::build_from_fqdn("upstream10"),
-                Upstream::build_from_fqdn("upstream11"),
-            ];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id4"),
-                String::from("route4"),
-                vec![String::from("^.+$")],
-                vec![String::from("uri4")],
-                strategy,
-            )
+        #[test]
+        fn should_normalize_host() {
+            assert_eq!("api.example.com", HostPattern::normalize("API.example.com:8080"));
+            assert_eq!("[::1]", HostPattern::normalize("[::1]:3000"));
+            assert_eq!("localhost", HostPattern::normalize("localhost"));
         }
 
-        fn sample_route_5_af() -> Route {
-            let upstreams = vec![
-                Upstream::build_from_fqdn("upstream20"),
-                Upstream::build_from_fqdn("upstream21"),
-            ];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id5"),
-                String::from("route5"),
-                vec![String::from("GET")],
-                vec![String::from("uri")],
-                strategy,
-            )
+        #[test]
+        fn should_match_wildcard_host_only_for_subdomains() {
+            // given:
+            let pattern = HostPattern::parse("*.example.com").unwrap();
+
+            // then:
+            assert!(pattern.matches(Some("www.example.com")));
+            assert!(pattern.matches(Some("a.b.example.com")));
+            assert!(!pattern.matches(Some("example.com")));
+            assert!(!pattern.matches(Some("www.example.org")));
+            assert!(!pattern.matches(None));
         }
 
-        fn sample_route_6_af() -> Route {
-            let upstreams = vec![
-                Upstream::build_from_fqdn("upstream21"),
-                Upstream::build_from_fqdn("upstream22"),
-            ];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id6"),
-                String::from("route6"),
-                vec![String::from("GET")],
-                vec![String::from("uri2")],
-                strategy,
-            )
+        #[test]
+        fn should_capture_template_params() {
+            // given:
+            let template = PathTemplate::parse("/users/{id}/orders/{order_id}").unwrap();
+
+            // when:
+            let params = template.captures("/users/7/orders/99").unwrap();
+
+            // then:
+            assert_eq!(
+                vec![
+                    (String::from("id"), String::from("7")),
+                    (String::from("order_id"), String::from("99")),
+                ],
+                params
+            );
         }
 
-        fn sample_route_7_af() -> Route {
-            let upstream1 = Upstream::build_from_fqdn("upstream20");
-            let mut upstream2 = Upstream::build_from_fqdn("upstream21");
-            upstream2.enabled = false;
-            let upstreams = vec![upstream1, upstream2];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id7"),
-                String::from("route7"),
-                vec![String::from("GET")],
-                vec![String::from("uri")],
-                strategy,
-            )
+        #[test]
+        fn should_not_capture_empty_template_param() {
+            // given:
+            let template = PathTemplate::parse("/users/{id}").unwrap();
+
+            // when:
+            let params = template.captures("/users/");
+
+            // then:
+            assert_eq!(None, params);
         }
 
-        fn sample_route_8_af() -> Route {
-            let mut upstream1 = Upstream::build_from_fqdn("upstream21");
-            upstream1.enabled = false;
-            let upstream2 = Upstream::build_from_fqdn("upstream22");
-            let upstreams = vec![upstream1, upstream2];
-            let strategy = AlwaysFirst { upstreams };
-            Route::build(
-                String::from("id8"),
-                String::from("route8"),
-                vec![String::from("GET")],
-                vec![String::from("uri2")],
-                strategy,
-            )
+        #[test]
+        fn should_not_match_template_with_extra_segments() {
+            // given:
+            let template = PathTemplate::parse("/users/{id}").unwrap();
+
+            // when:
+            let params = template.captures("/users/7/orders");
+
+            // then:
+            assert_eq!(None, params);
         }
-    }
-}
 
-pub(crate) mod route {
-    use crate::modules::core::upstream::UpstreamStrategy;
+        #[test]
+        fn should_capture_rest_of_path_for_prefix_template() {
+            // given:
+            let template = PathTemplate::parse("/static/*rest").unwrap();
 
-    #[derive(Clone, Debug, PartialEq)]
-    pub(crate) struct Route {
-        pub id: String,
-        pub name: String,
-        pub methods: Vec<String>,
-        pub paths: Vec<String>,
-        pub strategy: UpstreamStrategy,
-    }
+            // when:
+            let nested = template.captures("/static/js/app.js").unwrap();
+            let empty = template.captures("/static").unwrap();
 
-    impl Route {
-        pub fn build(
-            id: String,
-            name: String,
-            methods: Vec<String>,
-            paths: Vec<String>,
-            strategy: UpstreamStrategy,
-        ) -> Self {
-            Route { id, name, methods, paths, strategy }
+            // then:
+            assert_eq!(vec![(String::from("rest"), String::from("js/app.js"))], nested);
+            assert_eq!(vec![(String::from("rest"), String::new())], empty);
+        }
+
+        #[test]
+        fn should_match_unnamed_prefix_template_without_capturing() {
+            // given:
+            let template = PathTemplate::parse("/static/*").unwrap();
+
+            // when:
+            let params = template.captures("/static/js/app.js").unwrap();
+
+            // then:
+            assert!(params.is_empty());
+        }
+
+        #[test]
+        fn should_not_parse_literal_or_regexp_paths_as_templates() {
+            assert_eq!(None, PathTemplate::parse("/users"));
+            assert_eq!(None, PathTemplate::parse("^/users/.*$"));
+            assert_eq!(None, PathTemplate::parse("/users/\\d{3}"));
+            assert_eq!(None, PathTemplate::parse("/static/*rest/more"));
         }
     }
 }
 
 pub(crate) mod upstream {
+    use rand::rngs::SmallRng;
+    use rand::Rng;
+    use std::collections::HashMap;
     use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+
+    /// Number of requests currently being served by each upstream
+    pub(crate) type InFlightRequests = HashMap<UpstreamAddress, usize>;
+
+    /// Points each upstream gets on a consistent hash ring, per unit of weight, up to a maximum so
+    /// large weights can't blow up the ring. Weights above 100 all get the maximum
+    const RING_POINTS_PER_WEIGHT: u64 = 100;
+    const MAX_RING_POINTS_PER_UPSTREAM: u64 = 10_000;
 
     #[derive(Clone, Debug, Eq, PartialEq, Hash)]
     pub(crate) enum UpstreamAddress {
@@ -620,6 +2908,14 @@ pub(crate) mod upstream {
         }
     }
 
+    impl UpstreamAddress {
+        /// Opaque value identifying this upstream in affinity cookies, so clients don't get to see
+        /// internal addresses
+        pub fn affinity_token(&self) -> String {
+            format!("{:016x}", hash_of(self.to_string().as_str()))
+        }
+    }
+
     impl Display for UpstreamAddress {
         fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
             write!(f, "{:?}", self)
@@ -630,6 +2926,8 @@ pub(crate) mod upstream {
     pub(crate) struct Upstream {
         pub address: UpstreamAddress,
         pub enabled: bool,
+        /// Relative share of traffic under weighted strategies. A zero weight drains the upstream
+        pub weight: u32,
     }
 
     impl Upstream {
@@ -637,6 +2935,7 @@ pub(crate) mod upstream {
             Upstream {
                 address: UpstreamAddress::FQDN(fqdn.to_string()),
                 enabled: true,
+                weight: 1,
             }
         }
 
@@ -644,6 +2943,7 @@ pub(crate) mod upstream {
             Upstream {
                 address: UpstreamAddress::IPv4(ipv4),
                 enabled: true,
+                weight: 1,
             }
         }
     }
@@ -657,10 +2957,75 @@ pub(crate) mod upstream {
             upstreams: Vec<Upstream>,
             next_index: usize,
         },
+        /// Smooth weighted round-robin, as implemented by nginx: every pick raises each enabled
+        /// upstream's current weight by its weight, chooses the highest one and lowers it by the
+        /// total weight, which interleaves upstreams instead of sending bursts to the heaviest
+        WeightedRoundRobin {
+            upstreams: Vec<Upstream>,
+            current_weights: Vec<i64>,
+        },
+        /// Picks the enabled upstream with the fewest in-flight requests relative to its weight.
+        /// Ties are broken round-robin, starting at `next_index`
+        LeastConnections {
+            upstreams: Vec<Upstream>,
+            next_index: usize,
+        },
+        /// Hashes the given key onto a ring of upstream points, so the same key keeps landing on
+        /// the same upstream. When an upstream is disabled only its keys move, to the following
+        /// points on the ring. Requests without a key walk the ring point by point instead, which
+        /// spreads them in proportion to the weights. The ring is built on first use
+        ConsistentHash {
+            upstreams: Vec<Upstream>,
+            key: HashKey,
+            ring: Vec<(u64, usize)>, // (point, upstream index), sorted by point
+            next_index: usize,       // next point of the ring for requests without a key
+        },
+        /// Picks an enabled upstream at random, with a probability proportional to its weight
+        Random {
+            upstreams: Vec<Upstream>,
+            rng: SmallRng,
+        },
+        /// Picks two distinct enabled upstreams at random and keeps the one with the fewest
+        /// in-flight requests relative to its weight
+        PowerOfTwoChoices {
+            upstreams: Vec<Upstream>,
+            rng: SmallRng,
+        },
+        /// Keeps an exponentially weighted moving average of the latency of each upstream and
+        /// picks the one with the lowest average times its in-flight requests plus one, relative
+        /// to its weight, so a fast upstream stops getting everything once it queues requests.
+        /// Upstreams without latency samples are tried first. `decay` (between 0 and 1) is the
+        /// weight of the previous average against a new sample: higher values react slower
+        LeastLatency {
+            upstreams: Vec<Upstream>,
+            decay: f64,
+            latencies: Vec<Option<f64>>, // average latency in milliseconds, same order as upstreams
+        },
+        /// Tiers of upstreams in order of preference, each one balanced by its own strategy.
+        /// Requests go to the first tier with an available upstream, so a tier only gets traffic
+        /// when every upstream of the previous ones is disabled
+        Failover {
+            tiers: Vec<UpstreamStrategy>,
+        },
+    }
+
+    /// What a consistent hash strategy hashes to pick an upstream
+    #[derive(Clone, Debug, PartialEq)]
+    pub(crate) enum HashKey {
+        Client,
+        Header(String),
+        Cookie(String),
     }
 
     impl UpstreamStrategy {
-        pub fn next(&mut self) -> Option<&Upstream> {
+        /// Returns the next upstream to forward a request to, if any is enabled. Load-aware
+        /// strategies take the given in-flight requests into account,
+        /// and consistent hash strategies the given key
+        pub fn next(
+            &mut self,
+            in_flight: &InFlightRequests,
+            hash_key: Option<&str>,
+        ) -> Option<&Upstream> {
             match self {
                 UpstreamStrategy::AlwaysFirst { upstreams } => {
                     let mut result = None;
@@ -681,10 +3046,11 @@ pub(crate) mod upstream {
                             break;
                         }
 
-                        match upstreams.get(*next_index) {
+                        let index = *next_index;
+                        *next_index = (*next_index + 1) % upstreams.len();
+                        match upstreams.get(index) {
                             Some(ups) => {
                                 if ups.enabled {
-                                    *next_index = (*next_index + 1) % upstreams.len();
                                     result = Some(ups);
                                     break;
                                 }
@@ -697,62 +3063,372 @@ pub(crate) mod upstream {
 
                     result
                 },
+                UpstreamStrategy::WeightedRoundRobin { upstreams, current_weights } => {
+                    current_weights.resize(upstreams.len(), 0);
+                    let mut total_weight = 0;
+                    let mut selected: Option<usize> = None;
+
+                    for (index, upstream) in upstreams.iter().enumerate() {
+                        if !upstream.enabled || upstream.weight == 0 {
+                            continue;
+                        }
+                        current_weights[index] += upstream.weight as i64;
+                        total_weight += upstream.weight as i64;
+                        if selected.is_none_or(|s| current_weights[index] > current_weights[s]) {
+                            selected = Some(index);
+                        }
+                    }
+
+                    selected.map(move |index| {
+                        current_weights[index] -= total_weight;
+                        &upstreams[index]
+                    })
+                },
+                UpstreamStrategy::LeastConnections { upstreams, next_index } => {
+                    let mut selected: Option<usize> = None;
+
+                    for offset in 0..upstreams.len() {
+                        let index = (*next_index + offset) % upstreams.len();
+                        let upstream = &upstreams[index];
+                        if !upstream.enabled || upstream.weight == 0 {
+                            continue;
+                        }
+                        let is_best = selected
+                            .is_none_or(|s| is_less_loaded(upstream, &upstreams[s], in_flight));
+                        if is_best {
+                            selected = Some(index);
+                        }
+                    }
+
+                    selected.map(move |index| {
+                        *next_index = (index + 1) % upstreams.len();
+                        &upstreams[index]
+                    })
+                },
+                UpstreamStrategy::ConsistentHash { upstreams, ring, next_index, .. } => {
+                    if ring.is_empty() {
+                        *ring = build_ring(upstreams);
+                    }
+                    let upstreams: &Vec<Upstream> = upstreams;
+                    let start = match hash_key {
+                        Some(key) => {
+                            let hash = hash_of(key);
+                            ring.partition_point(|(point, _)| *point < hash)
+                        }
+                        None => {
+                            let start = *next_index % ring.len().max(1);
+                            *next_index = start + 1;
+                            start
+                        }
+                    };
+
+                    (0..ring.len())
+                        .map(|offset| ring[(start + offset) % ring.len()].1)
+                        .find(move |index| upstreams[*index].enabled)
+                        .map(move |index| &upstreams[index])
+                },
+                UpstreamStrategy::Random { upstreams, rng } => {
+                    let total_weight: u64 = available(upstreams).map(|u| u.weight as u64).sum();
+                    if total_weight == 0 {
+                        return None;
+                    }
+                    let mut remaining = rng.gen_range(0..total_weight);
+                    available(upstreams).find(|upstream| {
+                        if remaining < upstream.weight as u64 {
+                            true
+                        } else {
+                            remaining -= upstream.weight as u64;
+                            false
+                        }
+                    })
+                },
+                UpstreamStrategy::PowerOfTwoChoices { upstreams, rng } => {
+                    let candidates: Vec<&Upstream> = available(upstreams).collect();
+                    match candidates.len() {
+                        0 => None,
+                        1 => Some(candidates[0]),
+                        count => {
+                            let first = rng.gen_range(0..count);
+                            let mut second = rng.gen_range(0..count - 1);
+                            if second >= first {
+                                second += 1;
+                            }
+                            if is_less_loaded(candidates[second], candidates[first], in_flight) {
+                                Some(candidates[second])
+                            } else {
+                                Some(candidates[first])
+                            }
+                        }
+                    }
+                },
+                UpstreamStrategy::LeastLatency { upstreams, latencies, .. } => {
+                    latencies.resize(upstreams.len(), None);
+                    let score_of = |index: usize| {
+                        let upstream = &upstreams[index];
+                        let load = in_flight.get(&upstream.address).copied().unwrap_or(0);
+                        latencies[index]
+                            .map(|latency| latency * (load + 1) as f64 / upstream.weight as f64)
+                    };
+                    let mut selected: Option<(usize, Option<f64>)> = None;
+
+                    for (index, upstream) in upstreams.iter().enumerate() {
+                        if !upstream.enabled || upstream.weight == 0 {
+                            continue;
+                        }
+                        let score = score_of(index);
+                        let is_best = match (selected, score) {
+                            (None, _) => true,
+                            (Some((_, None)), _) => false,
+                            (Some((_, Some(_))), None) => true,
+                            (Some((_, Some(best))), Some(score)) => score < best,
+                        };
+                        if is_best {
+                            selected = Some((index, score));
+                        }
+                    }
+
+                    selected.map(move |(index, _)| &upstreams[index])
+                },
+                UpstreamStrategy::Failover { tiers } => {
+                    let active_tier = tiers.iter().position(|tier| tier.is_available())?;
+                    tiers[active_tier].next(in_flight, hash_key)
+                },
+            }
+        }
+
+        /// Whether `next` would return an upstream
+        pub fn is_available(&self) -> bool {
+            self.is_available_excluding(&[])
+        }
+
+        /// Same as `is_available`, but as if the given upstreams were disabled
+        pub fn is_available_excluding(&self, excluded: &[UpstreamAddress]) -> bool {
+            let usable = |u: &Upstream| u.enabled && !excluded.contains(&u.address);
+            match self {
+                UpstreamStrategy::AlwaysFirst { upstreams }
+                | UpstreamStrategy::RoundRobin { upstreams, .. } => upstreams.iter().any(usable),
+                UpstreamStrategy::Failover { tiers } => {
+                    tiers.iter().any(|t| t.is_available_excluding(excluded))
+                },
+                _ => self.get_upstreams().into_iter().any(|u| usable(u) && u.weight > 0),
+            }
+        }
+
+        /// Index of the tier currently serving a failover strategy, if any tier is available
+        /// once the given upstreams are left out
+        pub fn active_tier_excluding(&self, excluded: &[UpstreamAddress]) -> Option<usize> {
+            match self {
+                UpstreamStrategy::Failover { tiers } => {
+                    tiers.iter().position(|tier| tier.is_available_excluding(excluded))
+                },
+                _ => None,
+            }
+        }
+
+        /// What the request key is hashed on, for consistent hash strategies (including tiers)
+        pub fn hash_key(&self) -> Option<&HashKey> {
+            match self {
+                UpstreamStrategy::ConsistentHash { key, .. } => Some(key),
+                UpstreamStrategy::Failover { tiers } => tiers.iter().find_map(|t| t.hash_key()),
+                _ => None,
+            }
+        }
+
+        /// Feeds the latency of a completed request to the strategy. Only latency-aware strategies
+        /// make use of it
+        pub fn record_latency(&mut self, upstream_address: &UpstreamAddress, latency: Duration) {
+            match self {
+                UpstreamStrategy::LeastLatency { upstreams, decay, latencies } => {
+                    latencies.resize(upstreams.len(), None);
+                    let sample = latency.as_secs_f64() * 1000.0;
+                    for (index, upstream) in upstreams.iter().enumerate() {
+                        if upstream.address == *upstream_address {
+                            latencies[index] = Some(match latencies[index] {
+                                Some(average) => *decay * average + (1.0 - *decay) * sample,
+                                None => sample,
+                            });
+                        }
+                    }
+                },
+                UpstreamStrategy::Failover { tiers } => {
+                    for tier in tiers {
+                        tier.record_latency(upstream_address, latency)
+                    }
+                },
+                _ => {},
             }
         }
 
+        /// Same as `next`, but skipping the given upstreams as if they were disabled. Returns
+        /// `None` if every enabled upstream is excluded
+        pub fn next_excluding(
+            &mut self,
+            in_flight: &InFlightRequests,
+            hash_key: Option<&str>,
+            excluded: &[UpstreamAddress],
+        ) -> Option<UpstreamAddress> {
+            let mut hidden = Vec::new();
+            for upstream in self.upstreams_mut() {
+                if upstream.enabled && excluded.contains(&upstream.address) {
+                    upstream.enabled = false;
+                    hidden.push(upstream.address.clone());
+                }
+            }
+            let result = self.next(in_flight, hash_key).map(|u| u.address.clone());
+            for upstream in self.upstreams_mut() {
+                if hidden.contains(&upstream.address) {
+                    upstream.enabled = true;
+                }
+            }
+            result
+        }
+
         pub fn get_upstreams(&self) -> Vec<&Upstream> {
             match self {
                 UpstreamStrategy::AlwaysFirst { upstreams } => {
                     upstreams.iter().collect()
                 },
-                UpstreamStrategy::RoundRobin { upstreams, .. } => {
+                UpstreamStrategy::RoundRobin { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::WeightedRoundRobin { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::LeastConnections { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::ConsistentHash { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::Random { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::PowerOfTwoChoices { upstreams, .. } => {
                     upstreams.iter().collect()
                 },
+                UpstreamStrategy::LeastLatency { upstreams, .. } => {
+                    upstreams.iter().collect()
+                },
+                UpstreamStrategy::Failover { tiers } => {
+                    tiers.iter().flat_map(|tier| tier.get_upstreams()).collect()
+                },
             }
         }
 
         pub fn enable_upstream(&mut self, upstream_address: &UpstreamAddress) {
+            self.set_enabled(upstream_address, true)
+        }
+
+        pub fn disable_upstream(&mut self, upstream_address: &UpstreamAddress) {
+            self.set_enabled(upstream_address, false)
+        }
+
+        fn upstreams_mut(&mut self) -> Vec<&mut Upstream> {
             match self {
-                UpstreamStrategy::AlwaysFirst { upstreams } => {
-                    for u in upstreams {
-                        if u.address == *upstream_address {
-                            u.enabled = true;
-                        }
-                    }
+                UpstreamStrategy::AlwaysFirst { upstreams } => upstreams.iter_mut().collect(),
+                UpstreamStrategy::RoundRobin { upstreams, .. } => upstreams.iter_mut().collect(),
+                UpstreamStrategy::WeightedRoundRobin { upstreams, .. } => {
+                    upstreams.iter_mut().collect()
                 },
-                UpstreamStrategy::RoundRobin { upstreams, .. } => {
-                    for u in upstreams {
-                        if u.address == *upstream_address {
-                            u.enabled = true;
-                        }
-                    }
+                UpstreamStrategy::LeastConnections { upstreams, .. } => {
+                    upstreams.iter_mut().collect()
+                },
+                UpstreamStrategy::ConsistentHash { upstreams, .. } => {
+                    upstreams.iter_mut().collect()
+                },
+                UpstreamStrategy::Random { upstreams, .. } => upstreams.iter_mut().collect(),
+                UpstreamStrategy::PowerOfTwoChoices { upstreams, .. } => {
+                    upstreams.iter_mut().collect()
+                },
+                UpstreamStrategy::LeastLatency { upstreams, .. } => upstreams.iter_mut().collect(),
+                UpstreamStrategy::Failover { tiers } => {
+                    tiers.iter_mut().flat_map(|tier| tier.upstreams_mut()).collect()
                 },
             }
         }
 
-        pub fn disable_upstream(&mut self, upstream_address: &UpstreamAddress) {
-            match self {
-                UpstreamStrategy::AlwaysFirst { upstreams } => {
-                    for u in upstreams {
-                        if u.address == *upstream_address {
-                            u.enabled = false;
-                        }
+        fn set_enabled(&mut self, upstream_address: &UpstreamAddress, enabled: bool) {
+            let upstreams = match self {
+                UpstreamStrategy::AlwaysFirst { upstreams } => upstreams,
+                UpstreamStrategy::RoundRobin { upstreams, .. } => upstreams,
+                UpstreamStrategy::WeightedRoundRobin { upstreams, current_weights } => {
+                    // restart the smooth sequence so the new set of upstreams is spread evenly
+                    let changed = upstreams
+                        .iter()
+                        .any(|u| u.address == *upstream_address && u.enabled != enabled);
+                    if changed {
+                        current_weights.iter_mut().for_each(|w| *w = 0);
                     }
+                    upstreams
                 },
-                UpstreamStrategy::RoundRobin { upstreams, .. } => {
-                    for u in upstreams {
-                        if u.address == *upstream_address {
-                            u.enabled = false;
-                        }
+                UpstreamStrategy::LeastConnections { upstreams, .. } => upstreams,
+                UpstreamStrategy::ConsistentHash { upstreams, .. } => upstreams,
+                UpstreamStrategy::Random { upstreams, .. } => upstreams,
+                UpstreamStrategy::PowerOfTwoChoices { upstreams, .. } => upstreams,
+                UpstreamStrategy::LeastLatency { upstreams, .. } => upstreams,
+                UpstreamStrategy::Failover { tiers } => {
+                    for tier in tiers {
+                        tier.set_enabled(upstream_address, enabled)
                     }
+                    return;
                 },
+            };
+            for u in upstreams {
+                if u.address == *upstream_address {
+                    u.enabled = enabled;
+                }
+            }
+        }
+    }
+
+    /// Upstreams that may receive traffic: enabled and not drained
+    fn available(upstreams: &[Upstream]) -> impl Iterator<Item = &Upstream> {
+        upstreams.iter().filter(|u| u.enabled && u.weight > 0)
+    }
+
+    /// Whether `upstream` has fewer in-flight requests than `other`, relative to their weights
+    fn is_less_loaded(upstream: &Upstream, other: &Upstream, in_flight: &InFlightRequests) -> bool {
+        let load_of = |u: &Upstream| in_flight.get(&u.address).copied().unwrap_or(0) as u64;
+        // load / weight < other load / other weight, without dividing
+        load_of(upstream) * u64::from(other.weight) < load_of(other) * u64::from(upstream.weight)
+    }
+
+    fn build_ring(upstreams: &[Upstream]) -> Vec<(u64, usize)> {
+        let mut ring = Vec::new();
+        for (index, upstream) in upstreams.iter().enumerate() {
+            let address = upstream.address.to_string();
+            let points = u64::from(upstream.weight) * RING_POINTS_PER_WEIGHT;
+            for replica in 0..points.min(MAX_RING_POINTS_PER_UPSTREAM) {
+                ring.push((hash_of(format!("{}#{}", address, replica).as_str()), index));
             }
         }
+        ring.sort_unstable();
+        ring
+    }
+
+    /// Stable 64-bit hash (FNV-1a followed by the MurmurHash3 finalizer), so keys keep mapping to
+    /// the same upstreams across restarts
+    pub(crate) fn hash_of(key: &str) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in key.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+        hash ^ (hash >> 33)
     }
 
     #[cfg(test)]
     mod tests {
-        use crate::modules::core::upstream::{Upstream, UpstreamStrategy};
+        use crate::modules::core::upstream::{
+            HashKey, InFlightRequests, Upstream, UpstreamStrategy,
+        };
+        use rand::rngs::SmallRng;
+        use rand::SeedableRng;
+        use std::time::Duration;
 
         #[test]
         fn should_return_always_first() {
@@ -763,8 +3439,8 @@ pub(crate) mod upstream {
             let mut strategy = UpstreamStrategy::AlwaysFirst { upstreams };
 
             // when:
-            let first_result = strategy.next().unwrap().clone();
-            let second_result = strategy.next().unwrap().clone();
+            let first_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let second_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
 
             // then:
             assert_eq!(first_result, upstream1);
@@ -783,10 +3459,10 @@ pub(crate) mod upstream {
             };
 
             // when:
-            let first_result = strategy.next().unwrap().clone();
-            let second_result = strategy.next().unwrap().clone();
-            let third_result = strategy.next().unwrap().clone();
-            let fourth_result = strategy.next().unwrap().clone();
+            let first_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let second_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let third_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let fourth_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
 
             // then:
             assert_eq!(first_result, upstream1);
@@ -805,7 +3481,7 @@ pub(crate) mod upstream {
             let mut strategy = UpstreamStrategy::AlwaysFirst { upstreams };
 
             // when:
-            let result = strategy.next().unwrap().clone();
+            let result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
 
             // then:
             assert_eq!(result, upstream2);
@@ -822,12 +3498,444 @@ pub(crate) mod upstream {
             let mut strategy = UpstreamStrategy::AlwaysFirst { upstreams };
 
             // when:
-            let result = strategy.next();
+            let result = strategy.next(&InFlightRequests::new(), None);
+
+            // then:
+            assert_eq!(result, None);
+        }
+
+        #[test]
+        fn should_return_weighted_round_robin() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            upstream1.weight = 3;
+            let upstreams = vec![upstream1.clone(), upstream2.clone()];
+            let mut strategy = UpstreamStrategy::WeightedRoundRobin {
+                upstreams,
+                current_weights: Vec::new(),
+            };
+
+            // when:
+            let results: Vec<Upstream> = (0..8)
+                .map(|_| strategy.next(&InFlightRequests::new(), None).unwrap().clone())
+                .collect();
+
+            // then:
+            let expected = vec![
+                upstream1.clone(), upstream1.clone(), upstream2.clone(), upstream1.clone(),
+                upstream1.clone(), upstream1.clone(), upstream2.clone(), upstream1.clone(),
+            ];
+            assert_eq!(results, expected);
+        }
+
+        #[test]
+        fn should_keep_sequence_if_no_upstream_changes_wrr() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            upstream1.weight = 3;
+            let upstreams = vec![upstream1.clone(), upstream2.clone()];
+            let mut strategy = UpstreamStrategy::WeightedRoundRobin {
+                upstreams,
+                current_weights: Vec::new(),
+            };
+            let other = Upstream::build_from_fqdn("localhost:8082").address;
+
+            // when:
+            let mut results = Vec::new();
+            for _ in 0..2 {
+                results.push(strategy.next(&InFlightRequests::new(), None).unwrap().clone());
+            }
+            strategy.disable_upstream(&other);
+            strategy.enable_upstream(&upstream1.address);
+            for _ in 0..6 {
+                results.push(strategy.next(&InFlightRequests::new(), None).unwrap().clone());
+            }
+
+            // then:
+            let expected = vec![
+                upstream1.clone(), upstream1.clone(), upstream2.clone(), upstream1.clone(),
+                upstream1.clone(), upstream1.clone(), upstream2.clone(), upstream1.clone(),
+            ];
+            assert_eq!(results, expected);
+        }
+
+        #[test]
+        fn should_skip_disabled_and_drained_upstreams_wrr() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let mut upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream1.weight = 9;
+            upstream3.weight = 0;
+            let upstreams = vec![upstream1.clone(), upstream2.clone(), upstream3];
+            let mut strategy = UpstreamStrategy::WeightedRoundRobin {
+                upstreams,
+                current_weights: Vec::new(),
+            };
+
+            // when:
+            strategy.disable_upstream(&upstream1.address);
+            let first_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let second_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            strategy.enable_upstream(&upstream1.address);
+            let third_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, upstream2);
+            assert_eq!(second_result, upstream2);
+            assert_eq!(third_result, upstream1);
+        }
+
+        #[test]
+        fn should_return_least_connections() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let mut upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream3.weight = 4;
+            let upstreams = vec![upstream1.clone(), upstream2.clone(), upstream3.clone()];
+            let mut strategy = UpstreamStrategy::LeastConnections {
+                upstreams,
+                next_index: 0,
+            };
+            let mut in_flight = InFlightRequests::new();
+            in_flight.insert(upstream1.address.clone(), 2);
+            in_flight.insert(upstream3.address.clone(), 7);
+
+            // when:
+            let first_result = strategy.next(&in_flight, None).unwrap().clone();
+            in_flight.insert(upstream2.address.clone(), 2);
+            let second_result = strategy.next(&in_flight, None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, upstream2);
+            assert_eq!(second_result, upstream3);
+        }
+
+        #[test]
+        fn should_rotate_between_equally_loaded_upstreams_lc() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let mut upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1.clone(), upstream2, upstream3.clone()];
+            let mut strategy = UpstreamStrategy::LeastConnections {
+                upstreams,
+                next_index: 0,
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let first_result = strategy.next(&in_flight, None).unwrap().clone();
+            let second_result = strategy.next(&in_flight, None).unwrap().clone();
+            let third_result = strategy.next(&in_flight, None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, upstream1);
+            assert_eq!(second_result, upstream3);
+            assert_eq!(third_result, upstream1);
+        }
+
+        #[test]
+        fn should_return_same_upstream_for_same_key_ch() {
+            // given:
+            let upstreams = (0..4)
+                .map(|i| Upstream::build_from_fqdn(format!("localhost:808{}", i).as_str()))
+                .collect();
+            let mut strategy = UpstreamStrategy::ConsistentHash {
+                upstreams,
+                key: HashKey::Client,
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let first_result = strategy.next(&in_flight, Some("client1")).unwrap().clone();
+            let second_result = strategy.next(&in_flight, Some("client1")).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, second_result);
+        }
+
+        #[test]
+        fn should_only_move_keys_of_disabled_upstream_ch() {
+            // given:
+            let upstreams = (0..4)
+                .map(|i| Upstream::build_from_fqdn(format!("localhost:808{}", i).as_str()))
+                .collect();
+            let mut strategy = UpstreamStrategy::ConsistentHash {
+                upstreams,
+                key: HashKey::Client,
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let in_flight = InFlightRequests::new();
+            let keys: Vec<String> = (0..1000).map(|i| format!("client{}", i)).collect();
+            let before: Vec<Upstream> = keys
+                .iter()
+                .map(|k| strategy.next(&in_flight, Some(k.as_str())).unwrap().clone())
+                .collect();
+            let disabled = before[0].address.clone();
+
+            // when:
+            strategy.disable_upstream(&disabled);
+            let after: Vec<Upstream> = keys
+                .iter()
+                .map(|k| strategy.next(&in_flight, Some(k.as_str())).unwrap().clone())
+                .collect();
+
+            // then:
+            for (b, a) in before.iter().zip(after.iter()) {
+                if b.address == disabled {
+                    assert_ne!(a.address, disabled);
+                } else {
+                    assert_eq!(b.address, a.address);
+                }
+            }
+            let moved = before.iter().filter(|u| u.address == disabled).count();
+            assert!(moved > 150 && moved < 350, "uneven ring: {} keys moved", moved);
+        }
+
+        #[test]
+        fn should_spread_requests_without_key_ch() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            upstream1.weight = 3;
+            let mut strategy = UpstreamStrategy::ConsistentHash {
+                upstreams: vec![upstream1.clone(), upstream2],
+                key: HashKey::Cookie(String::from("session")),
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let results: Vec<Upstream> = (0..400)
+                .map(|_| strategy.next(&in_flight, None).unwrap().clone())
+                .collect();
+
+            // then:
+            let first_count = results.iter().filter(|u| u.address == upstream1.address).count();
+            assert_eq!(300, first_count);
+        }
+
+        #[test]
+        fn should_cap_ring_points_of_large_weights_ch() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            upstream1.weight = u32::MAX;
+            let mut strategy = UpstreamStrategy::ConsistentHash {
+                upstreams: vec![upstream1, upstream2],
+                key: HashKey::Client,
+                ring: Vec::new(),
+                next_index: 0,
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let result = strategy.next(&in_flight, Some("client1"));
+
+            // then:
+            assert!(result.is_some());
+            match strategy {
+                UpstreamStrategy::ConsistentHash { ring, .. } => assert_eq!(10_100, ring.len()),
+                _ => unreachable!(),
+            }
+        }
+
+        #[test]
+        fn should_return_random_in_proportion_to_weight() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let mut upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream1.weight = 3;
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1.clone(), upstream2, upstream3.clone()];
+            let mut strategy = UpstreamStrategy::Random {
+                upstreams,
+                rng: SmallRng::seed_from_u64(42),
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let results: Vec<Upstream> = (0..1000)
+                .map(|_| strategy.next(&in_flight, None).unwrap().clone())
+                .collect();
+
+            // then:
+            let first_count = results.iter().filter(|u| **u == upstream1).count();
+            let third_count = results.iter().filter(|u| **u == upstream3).count();
+            assert_eq!(1000, first_count + third_count);
+            assert!(first_count > 700 && first_count < 800, "{} hits", first_count);
+        }
+
+        #[test]
+        fn should_return_less_loaded_of_two_choices() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstreams = vec![upstream1.clone(), upstream2.clone()];
+            let mut strategy = UpstreamStrategy::PowerOfTwoChoices {
+                upstreams,
+                rng: SmallRng::seed_from_u64(42),
+            };
+            let mut in_flight = InFlightRequests::new();
+            in_flight.insert(upstream1.address.clone(), 5);
+
+            // when:
+            let results: Vec<Upstream> = (0..10)
+                .map(|_| strategy.next(&in_flight, None).unwrap().clone())
+                .collect();
+
+            // then:
+            assert!(results.iter().all(|u| *u == upstream2));
+        }
+
+        #[test]
+        fn should_return_none_if_upstreams_disabled_p2c() {
+            // given:
+            let mut upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let mut upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            upstream1.enabled = false;
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1, upstream2];
+            let mut strategy = UpstreamStrategy::PowerOfTwoChoices {
+                upstreams,
+                rng: SmallRng::seed_from_u64(42),
+            };
+
+            // when:
+            let result = strategy.next(&InFlightRequests::new(), None);
 
             // then:
             assert_eq!(result, None);
         }
 
+        #[test]
+        fn should_return_least_latency() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstreams = vec![upstream1.clone(), upstream2.clone()];
+            let mut strategy = UpstreamStrategy::LeastLatency {
+                upstreams,
+                decay: 0.5,
+                latencies: Vec::new(),
+            };
+            let mut in_flight = InFlightRequests::new();
+
+            // when:
+            let first_result = strategy.next(&in_flight, None).unwrap().clone();
+            strategy.record_latency(&upstream1.address, Duration::from_millis(100));
+            let second_result = strategy.next(&in_flight, None).unwrap().clone();
+            strategy.record_latency(&upstream2.address, Duration::from_millis(40));
+            let third_result = strategy.next(&in_flight, None).unwrap().clone();
+            strategy.record_latency(&upstream2.address, Duration::from_millis(200));
+            let fourth_result = strategy.next(&in_flight, None).unwrap().clone();
+            in_flight.insert(upstream1.address.clone(), 1);
+            let fifth_result = strategy.next(&in_flight, None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, upstream1); // no samples yet
+            assert_eq!(second_result, upstream2); // not sampled yet
+            assert_eq!(third_result, upstream2); // 40ms against 100ms
+            assert_eq!(fourth_result, upstream1); // (40 + 200) / 2 = 120ms against 100ms
+            assert_eq!(fifth_result, upstream2); // 120ms against 100ms * 2 in-flight
+        }
+
+        #[test]
+        fn should_fail_over_to_next_tier() {
+            // given:
+            let primary1 = Upstream::build_from_fqdn("localhost:8080");
+            let primary2 = Upstream::build_from_fqdn("localhost:8081");
+            let backup = Upstream::build_from_fqdn("localhost:9090");
+            let mut strategy = UpstreamStrategy::Failover {
+                tiers: vec![
+                    UpstreamStrategy::RoundRobin {
+                        upstreams: vec![primary1.clone(), primary2.clone()],
+                        next_index: 0,
+                    },
+                    UpstreamStrategy::AlwaysFirst { upstreams: vec![backup.clone()] },
+                ],
+            };
+            let in_flight = InFlightRequests::new();
+
+            // when:
+            let first_result = strategy.next(&in_flight, None).unwrap().clone();
+            let second_result = strategy.next(&in_flight, None).unwrap().clone();
+            strategy.disable_upstream(&primary1.address);
+            let third_result = strategy.next(&in_flight, None).unwrap().clone();
+            strategy.disable_upstream(&primary2.address);
+            let fourth_result = strategy.next(&in_flight, None).unwrap().clone();
+            let failed_over_tier = strategy.active_tier_excluding(&[]);
+            strategy.enable_upstream(&primary1.address);
+            let fifth_result = strategy.next(&in_flight, None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, primary1);
+            assert_eq!(second_result, primary2);
+            assert_eq!(third_result, primary2);
+            assert_eq!(fourth_result, backup);
+            assert_eq!(failed_over_tier, Some(1));
+            assert_eq!(fifth_result, primary1);
+            assert_eq!(strategy.active_tier_excluding(&[]), Some(0));
+        }
+
+        #[test]
+        fn should_skip_excluded_upstreams_rr() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let mut upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1.clone(), upstream2, upstream3.clone()];
+            let mut strategy = UpstreamStrategy::RoundRobin {
+                upstreams,
+                next_index: 0,
+            };
+            let excluded = vec![upstream1.address.clone()];
+
+            // when:
+            let in_flight = InFlightRequests::new();
+            let first_result = strategy.next_excluding(&in_flight, None, &excluded);
+            let second_result = strategy.next_excluding(&in_flight, None, &excluded);
+
+            // then:
+            assert_eq!(first_result, Some(upstream3.address.clone()));
+            assert_eq!(second_result, Some(upstream3.address));
+            let enabled: Vec<bool> = strategy.get_upstreams().iter().map(|u| u.enabled).collect();
+            assert_eq!(enabled, vec![true, false, true]);
+        }
+
+        #[test]
+        fn should_skip_disabled_upstream_rr() {
+            // given:
+            let upstream1 = Upstream::build_from_fqdn("localhost:8080");
+            let mut upstream2 = Upstream::build_from_fqdn("localhost:8081");
+            let upstream3 = Upstream::build_from_fqdn("localhost:8082");
+            upstream2.enabled = false;
+            let upstreams = vec![upstream1.clone(), upstream2, upstream3.clone()];
+            let mut strategy = UpstreamStrategy::RoundRobin {
+                upstreams,
+                next_index: 1,
+            };
+
+            // when:
+            let first_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+            let second_result = strategy.next(&InFlightRequests::new(), None).unwrap().clone();
+
+            // then:
+            assert_eq!(first_result, upstream3);
+            assert_eq!(second_result, upstream1);
+        }
+
         #[test]
         fn should_return_none_if_upstreams_disabled_rr() {
             // given:
@@ -842,7 +3950,7 @@ pub(crate) mod upstream {
             };
 
             // when:
-            let result = strategy.next();
+            let result = strategy.next(&InFlightRequests::new(), None);
 
             // then:
             assert_eq!(result, None);
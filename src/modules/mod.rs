@@ -1,3 +1,5 @@
+pub(crate) mod breaker;
 pub(crate) mod core;
+pub(crate) mod outlier;
 pub(crate) mod probe;
 pub(crate) mod stats;
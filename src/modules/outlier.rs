@@ -0,0 +1,381 @@
+use crate::modules::core::upstream::UpstreamAddress;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Upstream to take out of a route for the given time
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Ejection {
+    pub upstream_address: UpstreamAddress,
+    pub duration: Duration,
+}
+
+#[derive(Default)]
+struct UpstreamRecord {
+    requests: u64,     // since the last detection
+    failures: u64,     // 5xx responses, connection errors and timeouts since the last detection
+    responses: u64,    // requests that got a response since the last detection
+    latency: Duration, // total response time of those requests
+    ejections: u32,    // grows with each ejection, and shrinks while the upstream behaves
+    ejected: bool,
+}
+
+impl UpstreamRecord {
+    fn failure_rate(&self) -> f64 {
+        self.failures as f64 / self.requests as f64
+    }
+
+    fn mean_latency(&self) -> Option<f64> {
+        (self.responses > 0).then(|| self.latency.as_secs_f64() / self.responses as f64)
+    }
+}
+
+/// Passive outlier detection of the upstreams of a route, fed by the outcomes of the requests
+/// forwarded to them. Every detection compares each upstream that got enough requests since the
+/// previous one with its peers, and ejects those whose failure rate or mean latency is far worse
+/// than theirs. The more often an upstream is ejected, the longer it stays out, and there is a cap
+/// on the share of upstreams of the route ejected at once
+pub(crate) struct OutlierDetector {
+    min_requests: u64,
+    min_failure_rate: f64,
+    failure_factor: f64,
+    latency_factor: f64,
+    base_ejection: Duration,
+    max_ejection: Duration,
+    max_ejection_percent: u8,
+    upstreams: HashMap<UpstreamAddress, UpstreamRecord>,
+}
+
+impl OutlierDetector {
+    pub fn build(
+        min_requests: u64,
+        min_failure_rate: f64,
+        failure_factor: f64,
+        latency_factor: f64,
+        base_ejection: Duration,
+        max_ejection: Duration,
+        max_ejection_percent: u8,
+    ) -> Self {
+        OutlierDetector {
+            min_requests,
+            min_failure_rate,
+            failure_factor,
+            latency_factor,
+            base_ejection,
+            max_ejection,
+            max_ejection_percent,
+            upstreams: HashMap::new(),
+        }
+    }
+
+    /// Records the outcome of a request to the given upstream, along with its response time if
+    /// it got a response
+    pub fn record(
+        &mut self,
+        upstream_address: &UpstreamAddress,
+        latency: Option<Duration>,
+        failed: bool,
+    ) {
+        let record = self.upstreams.entry(upstream_address.clone()).or_default();
+        record.requests += 1;
+        if failed {
+            record.failures += 1;
+        }
+        if let Some(latency) = latency {
+            record.responses += 1;
+            record.latency += latency;
+        }
+    }
+
+    /// Returns the upstreams to eject, and starts counting requests again. Upstreams need at least
+    /// one peer with enough requests to be compared with. The share of upstreams ejected at once is
+    /// taken from the number of upstreams of the route, which may not all have got requests yet.
+    /// Upstreams are only taken out once their ejection is confirmed (see `eject`)
+    pub fn detect(&mut self, route_upstreams: usize) -> Vec<Ejection> {
+        let mut judged: Vec<(&UpstreamAddress, f64, Option<f64>)> = self
+            .upstreams
+            .iter()
+            .filter(|(_, record)| !record.ejected && record.requests >= self.min_requests)
+            .map(|(upstream, record)| (upstream, record.failure_rate(), record.mean_latency()))
+            .collect();
+        // the worst upstreams go first, in case the cap leaves some of them in
+        judged.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| b.2.unwrap_or(0.0).total_cmp(&a.2.unwrap_or(0.0)))
+                .then_with(|| a.0.to_string().cmp(&b.0.to_string()))
+        });
+
+        let mut outliers = Vec::new();
+        if judged.len() > 1 {
+            let upstreams = route_upstreams.max(self.upstreams.len());
+            let max_ejected = (upstreams * self.max_ejection_percent as usize / 100).max(1);
+            let mut ejected = self.upstreams.values().filter(|r| r.ejected).count();
+            for (upstream, failure_rate, latency) in judged.iter() {
+                if ejected >= max_ejected {
+                    break;
+                }
+                let peers: Vec<&(&UpstreamAddress, f64, Option<f64>)> = judged
+                    .iter()
+                    .filter(|(peer, _, _)| peer != upstream)
+                    .collect();
+                let peer_failure_rate =
+                    peers.iter().map(|(_, rate, _)| rate).sum::<f64>() / peers.len() as f64;
+                let peer_latencies: Vec<f64> = peers
+                    .iter()
+                    .filter_map(|(_, _, latency)| *latency)
+                    .collect();
+
+                let fails_more = *failure_rate >= self.min_failure_rate
+                    && *failure_rate >= self.failure_factor * peer_failure_rate;
+                let is_slower = match latency {
+                    Some(latency) if !peer_latencies.is_empty() => {
+                        let peer_latency =
+                            peer_latencies.iter().sum::<f64>() / peer_latencies.len() as f64;
+                        *latency > 0.0 && *latency >= self.latency_factor * peer_latency
+                    }
+                    _ => false,
+                };
+                if fails_more || is_slower {
+                    outliers.push((*upstream).clone());
+                    ejected += 1;
+                }
+            }
+        }
+
+        let mut result = Vec::new();
+        for (upstream, record) in self.upstreams.iter_mut() {
+            if outliers.contains(upstream) {
+                let duration = (self.base_ejection * (record.ejections + 1)).min(self.max_ejection);
+                result.push(Ejection {
+                    upstream_address: upstream.clone(),
+                    duration,
+                });
+            } else if !record.ejected {
+                record.ejections = record.ejections.saturating_sub(1);
+            }
+            record.requests = 0;
+            record.failures = 0;
+            record.responses = 0;
+            record.latency = Duration::ZERO;
+        }
+        result
+    }
+
+    /// Takes out an upstream returned by `detect`, so it isn't judged until restored, and its next
+    /// ejection lasts longer. Returns `true` if it wasn't ejected yet
+    pub fn eject(&mut self, upstream_address: &UpstreamAddress) -> bool {
+        match self.upstreams.get_mut(upstream_address) {
+            Some(record) if !record.ejected => {
+                record.ejections += 1;
+                record.ejected = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Brings back an ejected upstream. Returns `true` if it was ejected
+    pub fn restore(&mut self, upstream_address: &UpstreamAddress) -> bool {
+        match self.upstreams.get_mut(upstream_address) {
+            Some(record) if record.ejected => {
+                record.ejected = false;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::modules::core::upstream::UpstreamAddress;
+    use crate::modules::outlier::{Ejection, OutlierDetector};
+    use std::time::Duration;
+
+    #[test]
+    fn should_eject_upstream_failing_more_than_peers() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 6, 10);
+        record(&mut detector, "upstream2", 10, 1, 10);
+        record(&mut detector, "upstream3", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(3);
+
+        // then:
+        assert_eq!(
+            vec![Ejection {
+                upstream_address: address("upstream1"),
+                duration: Duration::from_secs(30),
+            }],
+            result
+        );
+    }
+
+    #[test]
+    fn should_eject_upstream_slower_than_peers() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 0, 10);
+        record(&mut detector, "upstream2", 10, 0, 100);
+        record(&mut detector, "upstream3", 10, 0, 12);
+
+        // when:
+        let result = detector.detect(3);
+
+        // then:
+        assert_eq!(1, result.len());
+        assert_eq!(address("upstream2"), result[0].upstream_address);
+    }
+
+    #[test]
+    fn should_not_eject_upstream_without_enough_requests() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 9, 9, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(2);
+
+        // then:
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn should_not_eject_upstreams_failing_as_much_as_peers() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 5, 10);
+        record(&mut detector, "upstream2", 10, 4, 10);
+
+        // when:
+        let result = detector.detect(2);
+
+        // then:
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn should_not_eject_more_than_max_ejection_percent() {
+        // given:
+        let mut detector = sample_detector(25);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 9, 10);
+        record(&mut detector, "upstream3", 10, 0, 10);
+        record(&mut detector, "upstream4", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(4);
+
+        // then:
+        assert_eq!(1, result.len());
+        assert_eq!(address("upstream1"), result[0].upstream_address);
+    }
+
+    #[test]
+    fn should_cap_ejections_on_upstreams_of_route() {
+        // given:
+        let mut detector = sample_detector(25);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 10, 10);
+        for upstream in ["upstream3", "upstream4", "upstream5", "upstream6"] {
+            record(&mut detector, upstream, 10, 0, 10);
+        }
+
+        // when:
+        let result = detector.detect(12);
+
+        // then:
+        assert_eq!(2, result.len());
+    }
+
+    #[test]
+    fn should_judge_upstream_whose_ejection_was_not_confirmed() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+        detector.detect(2);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(2);
+        let restored = detector.restore(&address("upstream1"));
+
+        // then:
+        assert_eq!(1, result.len());
+        assert_eq!(Duration::from_secs(30), result[0].duration);
+        assert!(!restored);
+    }
+
+    #[test]
+    fn should_eject_upstream_for_longer_each_time() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+        detector.detect(2);
+        detector.eject(&address("upstream1"));
+        detector.restore(&address("upstream1"));
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(2);
+
+        // then:
+        assert_eq!(Duration::from_secs(60), result[0].duration);
+    }
+
+    #[test]
+    fn should_not_judge_ejected_upstream_until_restored() {
+        // given:
+        let mut detector = sample_detector(100);
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+        detector.detect(2);
+        detector.eject(&address("upstream1"));
+        record(&mut detector, "upstream1", 10, 10, 10);
+        record(&mut detector, "upstream2", 10, 0, 10);
+
+        // when:
+        let result = detector.detect(2);
+        let restored = detector.restore(&address("upstream1"));
+
+        // then:
+        assert!(result.is_empty());
+        assert!(restored);
+    }
+
+    fn sample_detector(max_ejection_percent: u8) -> OutlierDetector {
+        OutlierDetector::build(
+            10,
+            0.2,
+            3.0,
+            3.0,
+            Duration::from_secs(30),
+            Duration::from_secs(300),
+            max_ejection_percent,
+        )
+    }
+
+    /// Records the given number of requests and failures, each taking the given milliseconds
+    fn record(
+        detector: &mut OutlierDetector,
+        upstream: &str,
+        requests: u64,
+        failures: u64,
+        latency_ms: u64,
+    ) {
+        for request in 0..requests {
+            let latency = Some(Duration::from_millis(latency_ms));
+            detector.record(&address(upstream), latency, request < failures);
+        }
+    }
+
+    fn address(upstream: &str) -> UpstreamAddress {
+        UpstreamAddress::FQDN(upstream.to_string())
+    }
+}
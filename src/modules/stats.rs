@@ -1,17 +1,119 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 pub(crate) struct Stats {
     // (client, method, path, upstream) => count
     counter: HashMap<(String, String, String, String), u64>,
+    // (route id, upstream, outcome) => count
+    outcomes: HashMap<(String, String, String), u64>,
+    // (route id, upstream) => (times ejected, whether it is ejected now)
+    ejections: HashMap<(String, String), (u64, bool)>,
+    // route id => (times failed over, times failed back, active tier)
+    failovers: HashMap<String, (u64, u64, Option<usize>)>,
+}
+
+/// Outcome of handling a request, other than a plain upstream response
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Outcome {
+    MirrorSucceeded,
+    MirrorFailed,
+    UpstreamTimedOut,
+    UpstreamRetried, // counted for the upstream that failed the attempt
+    RouteNotMatched, // counted without route nor upstream
+    NoHealthyUpstream, // counted for the matched route, without upstream
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::MirrorSucceeded => write!(f, "mirror_succeeded"),
+            Outcome::MirrorFailed => write!(f, "mirror_failed"),
+            Outcome::UpstreamTimedOut => write!(f, "upstream_timed_out"),
+            Outcome::UpstreamRetried => write!(f, "upstream_retried"),
+            Outcome::RouteNotMatched => write!(f, "route_not_matched"),
+            Outcome::NoHealthyUpstream => write!(f, "no_healthy_upstream"),
+        }
+    }
 }
 
 impl Stats {
     pub fn build() -> Self {
         Stats {
             counter: HashMap::new(),
+            outcomes: HashMap::new(),
+            ejections: HashMap::new(),
+            failovers: HashMap::new(),
+        }
+    }
+
+    pub fn count_failover(&mut self, route_id: &str, to_tier: Option<usize>) {
+        let (failed_over, _, active_tier) =
+            self.failovers.entry(route_id.to_string()).or_insert((0, 0, None));
+        *failed_over += 1;
+        *active_tier = to_tier;
+    }
+
+    pub fn count_failback(&mut self, route_id: &str, to_tier: usize) {
+        let (_, failed_back, active_tier) =
+            self.failovers.entry(route_id.to_string()).or_insert((0, 0, None));
+        *failed_back += 1;
+        *active_tier = Some(to_tier);
+    }
+
+    pub fn get_failovers(&self) -> Vec<(String, u64, u64, Option<usize>)> {
+        let mut result = Vec::new();
+
+        for entry in self.failovers.iter() {
+            result.push((entry.0.clone(), entry.1 .0, entry.1 .1, entry.1 .2))
+        }
+
+        result
+    }
+
+    pub fn count_ejection(&mut self, route_id: &str, upstream: &str) {
+        let key = (route_id.to_string(), upstream.to_string());
+        let (count, ejected) = self.ejections.entry(key).or_insert((0, false));
+        *count += 1;
+        *ejected = true;
+    }
+
+    pub fn count_restoration(&mut self, route_id: &str, upstream: &str) {
+        let key = (route_id.to_string(), upstream.to_string());
+        if let Some((_, ejected)) = self.ejections.get_mut(&key) {
+            *ejected = false;
         }
     }
 
+    pub fn get_ejections(&self) -> Vec<(String, String, u64, bool)> {
+        let mut result = Vec::new();
+
+        for entry in self.ejections.iter() {
+            result.push((entry.0 .0.clone(), entry.0 .1.clone(), entry.1 .0, entry.1 .1))
+        }
+
+        result
+    }
+
+    pub fn count_outcome(&mut self, route_id: &str, upstream: &str, outcome: &Outcome) {
+        let key = (route_id.to_string(), upstream.to_string(), outcome.to_string());
+        *self.outcomes.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn get_outcomes(&self) -> Vec<(String, String, String, u64)> {
+        let mut result = Vec::new();
+
+        for entry in self.outcomes.iter() {
+            result.push((
+                entry.0 .0.clone(),
+                entry.0 .1.clone(),
+                entry.0 .2.clone(),
+                *entry.1,
+            ))
+        }
+
+        result
+    }
+
     pub fn count_request(&mut self, client: &str, method: &str, path: &str, upstream: &str) {
         let key = (
             client.to_string(),